@@ -0,0 +1,334 @@
+//! Wall-clock benchmarks (via `criterion`) for the curve generation, the
+//! naive and Hilbert products (matrix-vector and matrix-matrix), the parallel
+//! product and coordinate table, and the width of the indices in the
+//! coordinate table. They complement the
+//! instruction counts of `my_benchmark` with timing distributions.
+//!
+//! Run with `cargo bench --bench criterion_benchmark`; add `--features rayon`
+//! for the parallel product and coordinate table.
+//!
+//! The `sweep` group runs `n = 2^8` up to `n = 2^SWEEP_MAX_ORDER` (default: 15).
+//! The coordinate table alone takes `24 * n^2` bytes (6 GiB at `2^14`), so
+//! lower `SWEEP_MAX_ORDER` on smaller machines.
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use jeremy_kun_math_rust::{
+    hilbert_matrix_matrix_product, hilbert_matrix_vector_product,
+    hilbert_matrix_vector_product_iter, hilbert_matrix_vector_product_windowed,
+    naive_matrix_matrix_product, naive_matrix_vector_product, seeded_rng, setup_hilbert,
+    setup_hilbert_iter, setup_inputs, Coordinates, HilbertIter, DEFAULT_SEED, DEFAULT_WINDOW_ORDER,
+};
+use std::hint::black_box;
+
+/// The cost of walking the L-system alone.
+fn bench_hilbert_iter(c: &mut Criterion) {
+    c.bench_function("hilbert_iter_count/12", |b| {
+        b.iter(|| HilbertIter::new(black_box(12)).count())
+    });
+}
+
+/// The naive product, the Hilbert product over a precomputed table, and the
+/// Hilbert product walking the curve as it goes, for `n` from `2^8` to `2^12`.
+fn bench_products(c: &mut Criterion) {
+    let mut group = c.benchmark_group("product");
+    for order in [8, 10, 12] {
+        let n = 2usize.pow(order);
+        group.throughput(Throughput::Elements((n * n) as u64));
+        let mut rng = seeded_rng(DEFAULT_SEED);
+        #[allow(non_snake_case)]
+        let (A, v) = setup_inputs(n, &mut rng);
+        #[allow(non_snake_case)]
+        let (coordinate_iter, flattened_A) = setup_hilbert(n, A.clone());
+        #[allow(non_snake_case)]
+        let (depth, flattened_A_iter) = setup_hilbert_iter(n, A.clone());
+        let mut output = vec![0; n];
+
+        group.bench_with_input(BenchmarkId::new("naive", n), &n, |b, &n| {
+            b.iter(|| naive_matrix_vector_product(black_box(&A), black_box(&v), &mut output, n))
+        });
+        group.bench_with_input(BenchmarkId::new("hilbert", n), &n, |b, _| {
+            b.iter(|| {
+                hilbert_matrix_vector_product(
+                    black_box(&flattened_A),
+                    black_box(&v),
+                    &mut output,
+                    black_box(&coordinate_iter),
+                )
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("hilbert_iter", n), &n, |b, _| {
+            b.iter(|| {
+                hilbert_matrix_vector_product_iter(
+                    black_box(&flattened_A_iter),
+                    black_box(&v),
+                    &mut output,
+                    black_box(depth),
+                )
+            })
+        });
+    }
+    group.finish();
+}
+
+/// The naive triple loop against the tiled Hilbert matrix-matrix product,
+/// for `n` from `2^6` to `2^8`.
+fn bench_matrix_products(c: &mut Criterion) {
+    let mut group = c.benchmark_group("matrix_product");
+    group.sample_size(10);
+    for order in [6, 7, 8] {
+        let n = 2usize.pow(order);
+        group.throughput(Throughput::Elements((n * n * n) as u64));
+        let mut rng = seeded_rng(DEFAULT_SEED);
+        #[allow(non_snake_case)]
+        let (A, _) = setup_inputs(n, &mut rng);
+        #[allow(non_snake_case)]
+        let (B, _) = setup_inputs(n, &mut rng);
+        #[allow(non_snake_case)]
+        let (coordinate_iter, flattened_A) = setup_hilbert(n, A.clone());
+        #[allow(non_snake_case)]
+        let (_, flattened_B) = setup_hilbert(n, B.clone());
+        let mut output = vec![0; n * n];
+
+        group.bench_with_input(BenchmarkId::new("naive", n), &n, |b, &n| {
+            b.iter(|| naive_matrix_matrix_product(black_box(&A), black_box(&B), &mut output, n))
+        });
+        group.bench_with_input(BenchmarkId::new("hilbert", n), &n, |b, _| {
+            b.iter(|| {
+                hilbert_matrix_matrix_product(
+                    black_box(&flattened_A),
+                    black_box(&flattened_B),
+                    &mut output,
+                    &coordinate_iter,
+                )
+            })
+        });
+    }
+    group.finish();
+}
+
+/// The naive product against the Hilbert product with the full coordinate
+/// table and with `DEFAULT_WINDOW_ORDER` windows, over sizes where the table
+/// goes from fitting in cache to not.
+fn bench_sweep(c: &mut Criterion) {
+    let max_order = match std::env::var("SWEEP_MAX_ORDER") {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("SWEEP_MAX_ORDER must be an integer, got {:?}", value)),
+        Err(_) => 15,
+    };
+    let mut group = c.benchmark_group("sweep");
+    group.sample_size(10);
+    for order in 8..=max_order {
+        let n = 2usize.pow(order);
+        group.throughput(Throughput::Elements((n * n) as u64));
+        let mut rng = seeded_rng(DEFAULT_SEED);
+        #[allow(non_snake_case)]
+        let (A, v) = setup_inputs(n, &mut rng);
+        let mut output = vec![0; n];
+        group.bench_with_input(BenchmarkId::new("naive", n), &n, |b, &n| {
+            b.iter(|| naive_matrix_vector_product(black_box(&A), black_box(&v), &mut output, n))
+        });
+        #[allow(non_snake_case)]
+        let (coordinate_iter, flattened_A) = setup_hilbert(n, A);
+        group.bench_with_input(BenchmarkId::new("hilbert", n), &n, |b, _| {
+            b.iter(|| {
+                hilbert_matrix_vector_product(
+                    black_box(&flattened_A),
+                    black_box(&v),
+                    &mut output,
+                    &coordinate_iter,
+                )
+            })
+        });
+        drop(coordinate_iter);
+        group.bench_with_input(BenchmarkId::new("hilbert_windowed", n), &n, |b, _| {
+            b.iter(|| {
+                hilbert_matrix_vector_product_windowed(
+                    black_box(&flattened_A),
+                    black_box(&v),
+                    &mut output,
+                    order as usize,
+                    DEFAULT_WINDOW_ORDER,
+                )
+            })
+        });
+    }
+    group.finish();
+}
+
+/// An unsigned integer a coordinate table can be stored in.
+trait Index: Copy + TryFrom<usize> {
+    fn get(self) -> usize;
+}
+
+impl Index for u16 {
+    fn get(self) -> usize {
+        self.into()
+    }
+}
+
+impl Index for u32 {
+    fn get(self) -> usize {
+        self as usize
+    }
+}
+
+impl Index for usize {
+    fn get(self) -> usize {
+        self
+    }
+}
+
+/// `coordinate_iter` with every index narrowed to `I`.
+fn narrow<I: Index>(coordinate_iter: &[(usize, Coordinates)]) -> Vec<(I, (I, I))> {
+    let narrow = |x: usize| I::try_from(x).unwrap_or_else(|_| panic!("{x} does not fit"));
+    coordinate_iter
+        .iter()
+        .map(|&(t, (i, j))| (narrow(t), (narrow(i), narrow(j))))
+        .collect()
+}
+
+/// `hilbert_matrix_vector_product` over a table of `I` indices.
+#[allow(non_snake_case)]
+fn product<I: Index>(flattened_A: &[i32], v: &[i32], output: &mut [i32], table: &[(I, (I, I))]) {
+    for &(t, (i, j)) in table {
+        output[i.get()] += flattened_A[t.get()] * v[j.get()];
+    }
+}
+
+/// The product with the coordinate table stored as `u16`, `u32` and `usize`.
+/// `u16` holds `t` only up to `n = 256`, so that's the largest size.
+fn bench_index_width(c: &mut Criterion) {
+    let mut group = c.benchmark_group("index_width");
+    for order in [6, 8] {
+        let n = 2usize.pow(order);
+        let mut rng = seeded_rng(DEFAULT_SEED);
+        #[allow(non_snake_case)]
+        let (A, v) = setup_inputs(n, &mut rng);
+        #[allow(non_snake_case)]
+        let (coordinate_iter, flattened_A) = setup_hilbert(n, A);
+        let table_u16 = narrow::<u16>(&coordinate_iter);
+        let table_u32 = narrow::<u32>(&coordinate_iter);
+        let table_usize = narrow::<usize>(&coordinate_iter);
+
+        let mut expected = vec![0; n];
+        hilbert_matrix_vector_product(&flattened_A, &v, &mut expected, &coordinate_iter);
+        let mut outputs = [vec![0; n], vec![0; n], vec![0; n]];
+        product(&flattened_A, &v, &mut outputs[0], &table_u16);
+        product(&flattened_A, &v, &mut outputs[1], &table_u32);
+        product(&flattened_A, &v, &mut outputs[2], &table_usize);
+        for output in &outputs {
+            assert_eq!(output, &expected);
+        }
+
+        let mut output = vec![0; n];
+        group.bench_with_input(BenchmarkId::new("u16", n), &n, |b, _| {
+            b.iter(|| {
+                product(
+                    black_box(&flattened_A),
+                    black_box(&v),
+                    &mut output,
+                    &table_u16,
+                )
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("u32", n), &n, |b, _| {
+            b.iter(|| {
+                product(
+                    black_box(&flattened_A),
+                    black_box(&v),
+                    &mut output,
+                    &table_u32,
+                )
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("usize", n), &n, |b, _| {
+            b.iter(|| {
+                product(
+                    black_box(&flattened_A),
+                    black_box(&v),
+                    &mut output,
+                    &table_usize,
+                )
+            })
+        });
+    }
+    group.finish();
+}
+
+#[cfg(feature = "rayon")]
+fn bench_parallel(c: &mut Criterion) {
+    use jeremy_kun_math_rust::hilbert_matrix_vector_product_parallel;
+
+    let mut group = c.benchmark_group("hilbert_product");
+    for order in [8, 10, 12] {
+        let n = 2usize.pow(order);
+        let mut rng = seeded_rng(DEFAULT_SEED);
+        #[allow(non_snake_case)]
+        let (A, v) = setup_inputs(n, &mut rng);
+        #[allow(non_snake_case)]
+        let (coordinate_iter, flattened_A) = setup_hilbert(n, A);
+        let mut output = vec![0; n];
+
+        group.bench_with_input(BenchmarkId::new("serial", n), &n, |b, _| {
+            b.iter(|| {
+                hilbert_matrix_vector_product(
+                    black_box(&flattened_A),
+                    black_box(&v),
+                    &mut output,
+                    &coordinate_iter,
+                )
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("parallel", n), &n, |b, _| {
+            b.iter(|| {
+                hilbert_matrix_vector_product_parallel(
+                    black_box(&flattened_A),
+                    black_box(&v),
+                    &mut output,
+                    &coordinate_iter,
+                )
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Building the depth-13 coordinate table serially from the grammar and in
+/// parallel from `hilbert_to_xy`.
+#[cfg(feature = "rayon")]
+fn bench_parallel_coordinates(c: &mut Criterion) {
+    use jeremy_kun_math_rust::hilbert_coordinates_parallel;
+
+    let depth = 13;
+    let mut group = c.benchmark_group("coordinate_table");
+    group.sample_size(10);
+    group.bench_function(BenchmarkId::new("serial", depth), |b| {
+        b.iter(|| HilbertIter::new(black_box(depth)).collect::<Vec<_>>())
+    });
+    group.bench_function(BenchmarkId::new("parallel", depth), |b| {
+        b.iter(|| hilbert_coordinates_parallel(black_box(depth)))
+    });
+    group.finish();
+}
+
+#[cfg(feature = "rayon")]
+criterion_group!(
+    benches,
+    bench_hilbert_iter,
+    bench_products,
+    bench_matrix_products,
+    bench_sweep,
+    bench_index_width,
+    bench_parallel,
+    bench_parallel_coordinates
+);
+#[cfg(not(feature = "rayon"))]
+criterion_group!(
+    benches,
+    bench_hilbert_iter,
+    bench_products,
+    bench_matrix_products,
+    bench_sweep,
+    bench_index_width
+);
+criterion_main!(benches);
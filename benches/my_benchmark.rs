@@ -0,0 +1,258 @@
+//! Instruction-count benchmarks (via `iai`) for the naive and Hilbert products.
+//!
+//! The problem size and the seed can be changed without editing this file:
+//!
+//! * `BENCH_ORDER`: the matrix is `2^BENCH_ORDER x 2^BENCH_ORDER` (default: 11).
+//! * `BENCH_SEED`: seed for the input generation (default: 10).
+//!
+//! Example: `BENCH_ORDER=9 BENCH_SEED=3 cargo bench --bench my_benchmark`
+//!
+//! iai counts the whole benchmark function, input generation included. The
+//! products therefore share `product_inputs`, and `bench_setup` runs it alone:
+//! subtract its count from `bench_naive` and `bench_hilbert` to compare the
+//! products themselves.
+use std::sync::OnceLock;
+
+use iai::black_box;
+use jeremy_kun_math_rust::{
+    flatten_matrix_into, hilbert_matrix_vector_product, hilbert_matrix_vector_product_dense,
+    hilbert_matrix_vector_product_soa, hilbert_matrix_vector_product_streaming, hilbert_transpose,
+    naive_matrix_vector_product, naive_matrix_vector_product_nested, naive_transpose, seeded_rng,
+    setup_hilbert, setup_inputs, CoordinateTable, Coordinates, HilbertIter, DEFAULT_SEED,
+};
+
+const DEFAULT_ORDER: u32 = 11;
+
+struct BenchConfig {
+    order: u32,
+    seed: u64,
+}
+
+/// Read `BENCH_ORDER` / `BENCH_SEED` once, falling back to the defaults.
+fn config() -> &'static BenchConfig {
+    static CONFIG: OnceLock<BenchConfig> = OnceLock::new();
+    CONFIG.get_or_init(|| BenchConfig {
+        order: env_or("BENCH_ORDER", DEFAULT_ORDER),
+        seed: env_or("BENCH_SEED", DEFAULT_SEED),
+    })
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("{} must be an integer, got {:?}", name, value)),
+        Err(_) => default,
+    }
+}
+
+/// Inputs for `bench_naive` and `bench_hilbert`: both layouts of `A` are
+/// always built, so the two benchmarks carry the same setup cost.
+#[allow(clippy::type_complexity)]
+fn product_inputs() -> (
+    usize,
+    Vec<i32>,
+    Vec<i32>,
+    Vec<(usize, Coordinates)>,
+    Vec<i32>,
+) {
+    let cfg = config();
+    let n = 2usize.pow(cfg.order);
+    let mut rng = seeded_rng(cfg.seed);
+    #[allow(non_snake_case)]
+    let (A, v) = setup_inputs(n, &mut rng);
+    #[allow(non_snake_case)]
+    let (coordinate_iter, flattened_A) = setup_hilbert(n, A.clone());
+    (n, A, v, coordinate_iter, flattened_A)
+}
+
+/// The setup baseline of `bench_naive` and `bench_hilbert`.
+fn bench_setup() {
+    black_box(product_inputs());
+}
+
+fn bench_naive() {
+    #[allow(non_snake_case)]
+    let (n, A, v, coordinate_iter, flattened_A) = product_inputs();
+    let mut output = vec![0; n];
+    naive_matrix_vector_product(black_box(&A), black_box(&v), &mut output, n);
+    black_box((output, coordinate_iter, flattened_A));
+}
+
+fn bench_naive_nested() {
+    let cfg = config();
+    let n = 2usize.pow(cfg.order);
+    let mut rng = seeded_rng(cfg.seed);
+    #[allow(non_snake_case)]
+    let (A, v) = setup_inputs(n, &mut rng);
+    let nested: Vec<Vec<i32>> = A.chunks(n).map(|row| row.to_vec()).collect();
+    let mut output = vec![0; n];
+    naive_matrix_vector_product_nested(black_box(&nested), black_box(&v), &mut output);
+    black_box(output);
+}
+
+fn bench_hilbert() {
+    #[allow(non_snake_case)]
+    let (n, A, v, coordinate_iter, flattened_A) = product_inputs();
+    let mut output = vec![0; n];
+    hilbert_matrix_vector_product(
+        black_box(&flattened_A),
+        black_box(&v),
+        &mut output,
+        &coordinate_iter,
+    );
+    black_box((output, A));
+}
+
+/// `bench_hilbert` with the curve stored without the sequential `t`.
+fn bench_hilbert_dense() {
+    let cfg = config();
+    let n = 2usize.pow(cfg.order);
+    let mut rng = seeded_rng(cfg.seed);
+    #[allow(non_snake_case)]
+    let (A, v) = setup_inputs(n, &mut rng);
+    #[allow(non_snake_case)]
+    let (coordinate_iter, flattened_A) = setup_hilbert(n, A);
+    let coordinates: Vec<Coordinates> = coordinate_iter.into_iter().map(|(_, ij)| ij).collect();
+    let mut output = vec![0; n];
+    hilbert_matrix_vector_product_dense(
+        black_box(&flattened_A),
+        black_box(&v),
+        &mut output,
+        &coordinates,
+    );
+    black_box(output);
+}
+
+/// Reads `A` in place, so unlike `bench_hilbert` there is no flattening step
+/// and no second `n * n` buffer.
+fn bench_hilbert_streaming() {
+    let cfg = config();
+    let n = 2usize.pow(cfg.order);
+    let mut rng = seeded_rng(cfg.seed);
+    #[allow(non_snake_case)]
+    let (A, v) = setup_inputs(n, &mut rng);
+    let mut output = vec![0; n];
+    hilbert_matrix_vector_product_streaming(
+        black_box(&A),
+        black_box(&v),
+        &mut output,
+        cfg.order as usize,
+    );
+    black_box(output);
+}
+
+/// How often the flattening benchmarks re-flatten the same matrix.
+const REFLATTEN_COUNT: usize = 4;
+
+/// Inputs for the flattening benchmarks: the matrix and its precomputed
+/// curve, so the two benchmarks differ only in how they get their buffers.
+fn flatten_inputs() -> (usize, Vec<i32>, Vec<(usize, Coordinates)>) {
+    let cfg = config();
+    let n = 2usize.pow(cfg.order);
+    let mut rng = seeded_rng(cfg.seed);
+    #[allow(non_snake_case)]
+    let (A, _) = setup_inputs(n, &mut rng);
+    let coordinate_iter = HilbertIter::new(cfg.order as usize).collect();
+    (n, A, coordinate_iter)
+}
+
+/// Re-flatten into a newly allocated buffer every time.
+fn bench_flatten_alloc() {
+    #[allow(non_snake_case)]
+    let (n, A, coordinate_iter) = flatten_inputs();
+    for _ in 0..REFLATTEN_COUNT {
+        let mut out = vec![0; n * n];
+        flatten_matrix_into(&coordinate_iter, black_box(&A), &mut out, n);
+        black_box(out);
+    }
+}
+
+/// Re-flatten with `flatten_matrix_into`, reusing one buffer.
+fn bench_flatten_into() {
+    #[allow(non_snake_case)]
+    let (n, A, coordinate_iter) = flatten_inputs();
+    let mut out = vec![0; n * n];
+    for _ in 0..REFLATTEN_COUNT {
+        flatten_matrix_into(&coordinate_iter, black_box(&A), &mut out, n);
+        black_box(&out);
+    }
+}
+
+/// Inputs for the layout benchmarks: both layouts are always built, so the
+/// two benchmarks differ only in which one the product reads.
+#[allow(clippy::type_complexity)]
+fn layout_inputs() -> (
+    Vec<i32>,
+    Vec<i32>,
+    Vec<(usize, Coordinates)>,
+    CoordinateTable,
+) {
+    let cfg = config();
+    let n = 2usize.pow(cfg.order);
+    let mut rng = seeded_rng(cfg.seed);
+    #[allow(non_snake_case)]
+    let (A, v) = setup_inputs(n, &mut rng);
+    #[allow(non_snake_case)]
+    let (coordinate_iter, flattened_A) = setup_hilbert(n, A);
+    let table = CoordinateTable::from(&coordinate_iter[..]);
+    (flattened_A, v, coordinate_iter, table)
+}
+
+/// Array-of-structs: `(t, (i, j))` tuples of `usize`.
+fn bench_layout_aos() {
+    #[allow(non_snake_case)]
+    let (flattened_A, v, coordinate_iter, table) = layout_inputs();
+    let mut output = vec![0; v.len()];
+    hilbert_matrix_vector_product(&flattened_A, &v, &mut output, black_box(&coordinate_iter));
+    black_box((output, table));
+}
+
+/// Struct-of-arrays: `t`, `i`, `j` as three parallel `u32` slices.
+fn bench_layout_soa() {
+    #[allow(non_snake_case)]
+    let (flattened_A, v, coordinate_iter, table) = layout_inputs();
+    let mut output = vec![0; v.len()];
+    hilbert_matrix_vector_product_soa(&flattened_A, &v, &mut output, black_box(&table));
+    black_box((output, coordinate_iter));
+}
+
+/// Both transposes build the curve, so they differ only in the traversal;
+/// iai's cache columns show the locality difference.
+fn transpose_inputs() -> (usize, Vec<i32>, Vec<(usize, Coordinates)>) {
+    let cfg = config();
+    let n = 2usize.pow(cfg.order);
+    let mut rng = seeded_rng(cfg.seed);
+    #[allow(non_snake_case)]
+    let (A, _) = setup_inputs(n, &mut rng);
+    let (coordinate_iter, _) = setup_hilbert(n, A.clone());
+    (n, A, coordinate_iter)
+}
+
+fn bench_transpose_naive() {
+    #[allow(non_snake_case)]
+    let (n, A, coordinate_iter) = transpose_inputs();
+    black_box(naive_transpose(black_box(&A), n));
+    black_box(coordinate_iter);
+}
+
+fn bench_transpose_hilbert() {
+    #[allow(non_snake_case)]
+    let (n, A, coordinate_iter) = transpose_inputs();
+    black_box(hilbert_transpose(black_box(&A), n, &coordinate_iter));
+}
+
+iai::main!(
+    bench_setup,
+    bench_naive,
+    bench_naive_nested,
+    bench_hilbert,
+    bench_hilbert_dense,
+    bench_hilbert_streaming,
+    bench_layout_aos,
+    bench_layout_soa,
+    bench_flatten_alloc,
+    bench_flatten_into,
+    bench_transpose_naive,
+    bench_transpose_hilbert
+);
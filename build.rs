@@ -0,0 +1,12 @@
+//! Sets `cfg(perf_counters)` when a performance counter backend is enabled
+//! for the target OS.
+
+fn main() {
+    println!("cargo::rerun-if-changed=build.rs");
+    println!("cargo::rustc-check-cfg=cfg(perf_counters)");
+    let os = std::env::var("CARGO_CFG_TARGET_OS").unwrap_or_default();
+    let feature = |name: &str| std::env::var_os(format!("CARGO_FEATURE_{}", name)).is_some();
+    if (os == "macos" && feature("MACOS_PERF")) || (os == "linux" && feature("LINUX_PERF")) {
+        println!("cargo::rustc-cfg=perf_counters");
+    }
+}
@@ -0,0 +1,31 @@
+//! Uses the curve generation from a `no_std` crate.
+#![cfg_attr(not(test), no_std)]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use jeremy_kun_math_rust::{hilbert_to_xy, xy_to_hilbert, Coordinates, HilbertIter};
+
+/// The Hilbert curve of `order`, as `HilbertIter` yields it.
+pub fn curve(order: usize) -> Vec<(usize, Coordinates)> {
+    HilbertIter::new(order).collect()
+}
+
+/// Index `t` mapped to its cell and back.
+pub fn round_trip(t: usize, order: usize) -> usize {
+    let (i, j) = hilbert_to_xy(t, order);
+    xy_to_hilbert(i, j, order)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_no_std_curve() {
+        for (t, (i, j)) in curve(4) {
+            assert_eq!(hilbert_to_xy(t, 4), (i, j));
+            assert_eq!(round_trip(t, 4), t);
+        }
+    }
+}
@@ -1,22 +1,70 @@
-#[allow(non_snake_case)]
+/// The original example from Jeremy Kun's Python code.
+use clap::Parser;
+#[cfg(perf_counters)]
+use jeremy_kun_math_rust::{compare_perf_counters, count_perf, PerformanceCounters};
 use jeremy_kun_math_rust::{
-    hilbert_matrix_vector_product, naive_matrix_vector_product, setup_hilbert, setup_inputs, Vector,
+    first_divergence, hilbert_matrix_vector_product_iter, log2_exact, pin_core,
+    product_may_overflow, product_stats, seeded_rng, DEFAULT_SEED, INPUT_MAX,
+};
+use jeremy_kun_math_rust::{
+    hilbert_matrix_matrix_product, hilbert_matrix_vector_product,
+    hilbert_matrix_vector_product_unchecked, naive_matrix_matrix_product,
+    naive_matrix_vector_product, setup_hilbert, setup_inputs, Vector,
 };
-use jeremy_kun_math_rust::{hilbert_matrix_vector_product_iter, log2};
-#[cfg(feature = "macos-perf")]
-use macos_perf::{compare_perf_counters, PerformanceCounters};
-/// The original example from Jeremy Kun's Python code.
-use rand::SeedableRng;
 use rand_chacha::ChaCha8Rng;
 use std::time::{self, Instant};
 use timeit::timeit_loops;
 
-fn main() -> eyre::Result<()> {
-    let mut rng = ChaCha8Rng::seed_from_u64(10);
-    #[cfg(feature = "macos-perf")]
-    macos_perf::init()?;
+/// The original example from Jeremy Kun's Python code: time the naive and the
+/// Hilbert matrix-vector products on one random matrix.
+#[derive(Parser)]
+struct Args {
+    /// Side of the square matrix; need not be a power of two. (Once the
+    /// coordinate table outgrows the caches, around 2^11, the Hilbert product
+    /// falls behind the naive one; see the README.)
+    #[arg(long, default_value_t = 2usize.pow(11))]
+    size: usize,
+    /// How often each product is run per measurement.
+    #[arg(long, alias = "repetitions", default_value_t = 20)]
+    reps: usize,
+    /// Seed for the random inputs.
+    #[arg(long, default_value_t = DEFAULT_SEED)]
+    seed: u64,
+    /// Pin the benchmark thread to this core (needs the `core_affinity` feature).
+    #[arg(long)]
+    core: Option<usize>,
+}
 
-    let n: usize = 2usize.pow(11); // I observed a slowdown for the Hilbert code with '2^14'.
+fn main() -> eyre::Result<()> {
+    let args = match Args::try_parse() {
+        Ok(args) => args,
+        // `--help` and `--version` aren't errors.
+        Err(e) if !e.use_stderr() => e.exit(),
+        // clap's message already starts with "error: ".
+        Err(e) => eyre::bail!("{}", e.to_string().trim_start_matches("error: ").trim_end()),
+    };
+    let n = args.size;
+    eyre::ensure!(n > 0, "--size must be positive");
+    eyre::ensure!(
+        n.checked_mul(n).is_some(),
+        "--size {} is too large: the matrix would have more than usize::MAX entries",
+        n
+    );
+    eyre::ensure!(args.reps > 0, "--reps must be positive");
+    if !pin_core(args.core)? && cfg!(feature = "core_affinity") {
+        eprintln!(
+            "warning: could not pin the benchmark thread to core {}",
+            args.core.unwrap_or(0)
+        );
+    }
+    if product_may_overflow(n, INPUT_MAX) {
+        eprintln!(
+            "warning: the product of a {}x{} matrix with entries up to {} may overflow i32; \
+             consider a wider accumulator",
+            n, n, INPUT_MAX
+        );
+    }
+    let mut rng = seeded_rng(args.seed);
 
     let start = time::Instant::now();
 
@@ -26,19 +74,20 @@ fn main() -> eyre::Result<()> {
     let mut output1: Vector = vec![0; n];
     let mut output2: Vector = vec![0; n];
     let mut output3: Vector = vec![0; n];
+    let mut output4: Vector = vec![0; n];
     let end = time::Instant::now();
     println!("Initial data generation: {}s", (end - start).as_secs_f32());
 
     // Naive
-    let timeit_count = 20;
+    let timeit_count = args.reps;
     let total_n_seconds = timeit_loops! {timeit_count,
         {  naive_matrix_vector_product(&A, &v, &mut output1, n); }
     };
 
-    #[cfg(feature = "macos-perf")]
-    let pc_naive = macos_perf::timeit_loops! {timeit_count,
-        {  naive_matrix_vector_product(&A, &v, &mut output1, n); }
-    }?;
+    #[cfg(perf_counters)]
+    let pc_naive = count_perf(timeit_count, || {
+        naive_matrix_vector_product(&A, &v, &mut output1, n);
+    })?;
 
     // reorder data
     let start = Instant::now();
@@ -47,6 +96,7 @@ fn main() -> eyre::Result<()> {
     let (hilbert_iter, flattened_A) = setup_hilbert(n, A);
 
     let end = Instant::now();
+    println!("Hilbert matrix size: {}", hilbert_iter.len());
     println!(
         "hilbert data preprocessing: {}s",
         (end - start).as_secs_f32()
@@ -57,41 +107,123 @@ fn main() -> eyre::Result<()> {
         {hilbert_matrix_vector_product(&flattened_A,&v, &mut output2, &hilbert_iter);}
     };
 
-    #[cfg(feature = "macos-perf")]
-    let pc_hilbert = macos_perf::timeit_loops! {timeit_count,
-        {  hilbert_matrix_vector_product(&flattened_A, &v, &mut output2, &hilbert_iter); }
-    }?;
+    #[cfg(perf_counters)]
+    let pc_hilbert = count_perf(timeit_count, || {
+        hilbert_matrix_vector_product(&flattened_A, &v, &mut output2, &hilbert_iter);
+    })?;
 
-    assert_eq!(output1, output2);
+    check_outputs("Hilbert", &output1, &output2)?;
 
-    // Hilbert Product Iterator
-    let depth = log2(n);
-    let total_hilbert_iter_seconds = timeit_loops! {timeit_count,
-        {hilbert_matrix_vector_product_iter(&flattened_A, &v, &mut output3, depth);}
+    // Hilbert Product without bounds checks.
+    // Safety: `hilbert_iter` comes from `setup_hilbert(n, ..)` and `v` has length `n`.
+    let total_hilbert_unchecked_seconds = timeit_loops! {timeit_count,
+        {unsafe { hilbert_matrix_vector_product_unchecked(&flattened_A, &v, &mut output4, &hilbert_iter) };}
     };
 
-    #[cfg(feature = "macos-perf")]
-    let pc_hilbert_iter = macos_perf::timeit_loops! {timeit_count,
-        {  hilbert_matrix_vector_product_iter(&flattened_A, &v, &mut output3, depth); }
-    }?;
-    assert_eq!(output1, output3);
+    #[cfg(perf_counters)]
+    let pc_hilbert_unchecked = count_perf(timeit_count, || {
+        unsafe {
+            hilbert_matrix_vector_product_unchecked(&flattened_A, &v, &mut output4, &hilbert_iter)
+        };
+    })?;
+    check_outputs("Hilbert (unchecked)", &output1, &output4)?;
+
+    // Hilbert Product Iterator. It walks the whole curve, so it needs a power of two.
+    let mut total_hilbert_iter_seconds = None;
+    #[cfg(perf_counters)]
+    let mut pc_hilbert_iter = None;
+    if let Some(depth) = log2_exact(n) {
+        total_hilbert_iter_seconds = Some(timeit_loops! {timeit_count,
+            {hilbert_matrix_vector_product_iter(&flattened_A, &v, &mut output3, depth);}
+        });
+
+        #[cfg(perf_counters)]
+        {
+            pc_hilbert_iter = Some(count_perf(timeit_count, || {
+                hilbert_matrix_vector_product_iter(&flattened_A, &v, &mut output3, depth);
+            })?);
+        }
+        check_outputs("Hilbert (iter)", &output1, &output3)?;
+    }
+    println!("Output: {:?}", product_stats(&output1));
 
     print_timings(
         total_n_seconds,
         total_h_seconds,
         total_hilbert_iter_seconds,
+        total_hilbert_unchecked_seconds,
         timeit_count as f64,
     );
 
-    #[cfg(feature = "macos-perf")]
-    print_perf_counters(pc_naive, pc_hilbert, pc_hilbert_iter);
+    #[cfg(perf_counters)]
+    print_perf_counters(pc_naive, pc_hilbert, pc_hilbert_iter, pc_hilbert_unchecked);
+
+    compare_matrix_products(&mut rng)?;
+    Ok(())
+}
+
+/// Time the naive and the Hilbert matrix-matrix product on a smaller matrix.
+fn compare_matrix_products(rng: &mut ChaCha8Rng) -> eyre::Result<()> {
+    let n = 2usize.pow(8);
+    let timeit_count = 3;
+    #[allow(non_snake_case)]
+    let (A, _) = setup_inputs(n, rng);
+    #[allow(non_snake_case)]
+    let (B, _) = setup_inputs(n, rng);
+    let mut naive = vec![0; n * n];
+    let mut hilbert = vec![0; n * n];
+
+    let total_n_seconds = timeit_loops! {timeit_count,
+        {  naive_matrix_matrix_product(&A, &B, &mut naive, n); }
+    };
+    #[cfg(perf_counters)]
+    let pc_naive = count_perf(timeit_count, || {
+        naive_matrix_matrix_product(&A, &B, &mut naive, n);
+    })?;
+
+    #[allow(non_snake_case)]
+    let (hilbert_iter, flattened_A) = setup_hilbert(n, A);
+    #[allow(non_snake_case)]
+    let (_, flattened_B) = setup_hilbert(n, B);
+    let total_h_seconds = timeit_loops! {timeit_count,
+        {  hilbert_matrix_matrix_product(&flattened_A, &flattened_B, &mut hilbert, &hilbert_iter); }
+    };
+    #[cfg(perf_counters)]
+    let pc_hilbert = count_perf(timeit_count, || {
+        hilbert_matrix_matrix_product(&flattened_A, &flattened_B, &mut hilbert, &hilbert_iter);
+    })?;
+
+    println!(
+        "Matrix-matrix ({}x{}): naive {}s, Hilbert {}s",
+        n, n, total_n_seconds, total_h_seconds
+    );
+    #[cfg(perf_counters)]
+    println!(
+        "Comparison (matrix-matrix): {}",
+        compare_perf_counters(&pc_naive, &pc_hilbert)
+    );
+    Ok(())
+}
+
+/// Fail with the first differing entry if `output` disagrees with the naive result.
+fn check_outputs(label: &str, naive: &[i32], output: &[i32]) -> eyre::Result<()> {
+    if let Some((index, expected, actual)) = first_divergence(naive, output) {
+        eyre::bail!(
+            "{} result differs from naive at index {}: expected {}, got {}",
+            label,
+            index,
+            expected,
+            actual
+        );
+    }
     Ok(())
 }
 
 fn print_timings(
     total_n_seconds: f64,
     total_h_seconds: f64,
-    total_hilbert_iter_seconds: f64,
+    total_hilbert_iter_seconds: Option<f64>,
+    total_hilbert_unchecked_seconds: f64,
     timeit_count: f64,
 ) {
     println!(
@@ -104,34 +236,57 @@ fn print_timings(
         total_h_seconds,
         total_h_seconds / timeit_count
     );
+    match total_hilbert_iter_seconds {
+        Some(seconds) => println!(
+            "Hilbert (iter): {:+e}s ({:+e} s per)",
+            seconds,
+            seconds / timeit_count
+        ),
+        None => println!("Hilbert (iter): skipped, the size is not a power of two"),
+    }
     println!(
-        "Hilbert (iter): {:+e}s ({:+e} s per)",
-        total_hilbert_iter_seconds,
-        total_hilbert_iter_seconds / timeit_count
+        "Hilbert (unchecked): {:+e}s ({:+e} s per)",
+        total_hilbert_unchecked_seconds,
+        total_hilbert_unchecked_seconds / timeit_count
     );
+    let improvement = |seconds: f64| 100. * (1.0 - (seconds / total_n_seconds));
     println!(
-        "Improvement: {}% {}%",
-        100. * (1.0 - (total_h_seconds / total_n_seconds)),
-        100. * (1.0 - (total_hilbert_iter_seconds / total_n_seconds))
+        "Improvement: {}% {} {}%",
+        improvement(total_h_seconds),
+        total_hilbert_iter_seconds
+            .map(|seconds| format!("{}%", improvement(seconds)))
+            .unwrap_or_else(|| "-".to_string()),
+        improvement(total_hilbert_unchecked_seconds)
     );
 }
 
 /// Print performance counters.
-#[cfg(feature = "macos-perf")]
+#[cfg(perf_counters)]
 fn print_perf_counters(
     pc_naive: PerformanceCounters,
     pc_hilbert: PerformanceCounters,
-    pc_hilbert_iter: PerformanceCounters,
+    pc_hilbert_iter: Option<PerformanceCounters>,
+    pc_hilbert_unchecked: PerformanceCounters,
 ) {
     println!("Naive: {:?}", pc_naive);
     println!("Hilbert: {:?}", pc_hilbert);
-    println!("Hilbert (iter): {:?}", pc_hilbert_iter);
+    if let Some(pc_hilbert_iter) = &pc_hilbert_iter {
+        println!("Hilbert (iter): {:?}", pc_hilbert_iter);
+    }
+    println!("Hilbert (unchecked): {:?}", pc_hilbert_unchecked);
     println!(
         "Comparison: {}",
         compare_perf_counters(&pc_naive, &pc_hilbert)
     );
+    if let Some(pc_hilbert_iter) = &pc_hilbert_iter {
+        println!(
+            "Comparison (iter): {}",
+            compare_perf_counters(&pc_naive, pc_hilbert_iter)
+        );
+    }
+    // Bounds checks are branches, so this is where `missed_branches` should drop.
     println!(
-        "Comparison (iter): {}",
-        compare_perf_counters(&pc_naive, &pc_hilbert_iter)
+        "Comparison (unchecked vs. checked): {}",
+        compare_perf_counters(&pc_hilbert, &pc_hilbert_unchecked)
     );
 }
@@ -1,82 +1,94 @@
+use clap::Parser;
+#[cfg(perf_counters)]
+use jeremy_kun_math_rust::count_perf;
 use jeremy_kun_math_rust::{
-    hilbert_matrix_vector_product, naive_matrix_vector_product, setup_hilbert, setup_inputs,
+    pin_core, run_experiment, write_csv, CurveKind, ExperimentConfig, DEFAULT_BLOCK, DEFAULT_SEED,
 };
-#[cfg(feature = "macos-perf")]
-use macos_perf::PerformanceCounters;
-use rand::SeedableRng;
-use rand_chacha::ChaCha8Rng;
+#[cfg(feature = "rayon")]
+use jeremy_kun_math_rust::{run_scaling, ExperimentRow};
 
 /// Experiment to compare Performance Counter with runtime.
 /// Is PerfCounter a linear regression for runtime?
 ///
-
-// use time::Timespec;
+/// Writes one CSV row per implementation and matrix size to stdout. With the
+/// `rayon` feature, the parallel Hilbert product and coordinate generation are
+/// also timed for 1, 2, 4, ... up to `--threads` threads.
+#[derive(Parser)]
+struct Args {
+    /// Seed for the random inputs.
+    #[arg(long, default_value_t = DEFAULT_SEED)]
+    seed: u64,
+    /// Tile side of the blocked baseline.
+    #[arg(long, default_value_t = DEFAULT_BLOCK)]
+    block: usize,
+    /// Largest thread count of the scaling runs (needs the `rayon` feature);
+    /// one per core by default.
+    #[arg(long)]
+    threads: Option<usize>,
+    /// Pin the benchmark thread to this core (needs the `core_affinity` feature).
+    #[arg(long)]
+    core: Option<usize>,
+}
 
 fn main() -> eyre::Result<()> {
     color_eyre::install()?;
-    #[cfg(feature = "macos-perf")]
-    macos_perf::init()?;
-
-    let mut rng = ChaCha8Rng::seed_from_u64(10);
-    let timeit_count = 20;
-
-    let matrix_sizes = (5..14).map(|n| 2usize.pow(n)).collect::<Vec<_>>();
 
-    for n in matrix_sizes.iter() {
-        #[allow(non_snake_case)]
-        let (A, v) = setup_inputs(*n, &mut rng);
-        let mut output1 = vec![0; *n];
-
-        let total_n_seconds = timeit::timeit_loops! {timeit_count,
-            {  naive_matrix_vector_product(&A, &v, &mut output1, *n); }
-        };
-
-        #[cfg(feature = "macos-perf")]
-        let pc_naive = macos_perf::timeit_loops! {timeit_count,
-            {  naive_matrix_vector_product(&A, &v, &mut output1, *n); }
-        }?;
-        #[cfg(feature = "macos-perf")]
-        print_row("naive", *n, total_n_seconds, pc_naive);
-        #[cfg(not(feature = "macos-perf"))]
-        println!("naive, {}, {}", *n, total_n_seconds);
+    let args = match Args::try_parse() {
+        Ok(args) => args,
+        // `--help` and `--version` aren't errors.
+        Err(e) if !e.use_stderr() => e.exit(),
+        // clap's message already starts with "error: ".
+        Err(e) => eyre::bail!("{}", e.to_string().trim_start_matches("error: ").trim_end()),
+    };
+    eyre::ensure!(args.block > 0, "--block must be positive");
+    eyre::ensure!(args.threads != Some(0), "--threads must be positive");
+    #[cfg(not(feature = "rayon"))]
+    if args.threads.is_some() {
+        eyre::bail!("--threads requires the `rayon` feature");
     }
 
-    // Re-seed RNG.
-    let mut rng = ChaCha8Rng::seed_from_u64(10);
-    for n in matrix_sizes {
-        #[allow(non_snake_case)]
-        let (A, v) = setup_inputs(n, &mut rng);
-        #[allow(non_snake_case)]
-        let (coordinate_iter, flattened_A) = setup_hilbert(n, A);
-        let mut output = vec![0; n];
+    // Pin the thread that runs the serial benchmarks.
+    if !pin_core(args.core)? && cfg!(feature = "core_affinity") {
+        eprintln!(
+            "warning: could not pin the benchmark thread to core {}",
+            args.core.unwrap_or(0)
+        );
+    }
 
-        // Hilbert Product
-        let total_h_seconds = timeit::timeit_loops! {timeit_count,
-            {hilbert_matrix_vector_product(&flattened_A,&v, &mut output, &coordinate_iter);}
-        };
+    #[cfg(perf_counters)]
+    if let Err(e) = count_perf(1, || ()) {
+        eprintln!(
+            "warning: could not read the performance counters, leaving their columns empty: {}",
+            e
+        );
+    }
 
-        #[cfg(feature = "macos-perf")]
-        let pc_hilbert = macos_perf::timeit_loops! {timeit_count,
-            {  hilbert_matrix_vector_product(&flattened_A, &v, &mut output, &coordinate_iter); }
-        }?;
-        #[cfg(feature = "macos-perf")]
-        print_row("hilbert", n, total_h_seconds, pc_hilbert);
-        #[cfg(not(feature = "macos-perf"))]
-        println!("hilbert, {}, {}", n, total_h_seconds);
+    let mut rows = Vec::new();
+    for curve in CurveKind::variants() {
+        let cfg = ExperimentConfig::builder()
+            .seed(args.seed)
+            .curve(curve)
+            .block(args.block)
+            .build();
+        rows.extend(run_experiment(&cfg));
     }
+    #[cfg(feature = "rayon")]
+    rows.extend(scaling(&args)?);
+    write_csv(&rows, &mut std::io::stdout().lock())?;
     Ok(())
 }
 
-#[cfg(feature = "macos-perf")]
-fn print_row(label: &str, n: usize, total_n_seconds: f64, pc_naive: PerformanceCounters) {
-    println!(
-        "{}, {}, {}, {}, {}, {}, {}",
-        label,
-        n,
-        total_n_seconds,
-        pc_naive.cycles,
-        pc_naive.branches,
-        pc_naive.missed_branches,
-        pc_naive.instructions
-    );
+/// The parallel rows for 1, 2, 4, ... threads, up to and including
+/// `--threads`.
+#[cfg(feature = "rayon")]
+fn scaling(args: &Args) -> eyre::Result<Vec<ExperimentRow>> {
+    let max = args
+        .threads
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+    let mut threads: Vec<_> = std::iter::successors(Some(1), |t| Some(t * 2))
+        .take_while(|&t| t < max)
+        .collect();
+    threads.push(max);
+    let cfg = ExperimentConfig::builder().seed(args.seed).build();
+    run_scaling(&cfg, &threads)
 }
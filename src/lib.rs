@@ -1,298 +1,5188 @@
-/** Algorithms for converting 2D coordinates to and from the Hilbert index.
+/*! Algorithms for converting 2D coordinates to and from the Hilbert index.
 
 Here the Hilbert curve has been scaled and discretized, so that the
 range {0, 1, ..., n^2 - 1} is mapped to coordinates
 {0, 1, ..., n-1} x {0, 1, ..., n-1}. In the classical Hilbert curve,
 the continuous interval [0,1] is mapped to the unit square [0,1]^2.
 */
-use rand::{distributions::Uniform, Rng};
-use rand_chacha::ChaCha8Rng;
-use std::collections::VecDeque;
+#![cfg_attr(not(feature = "std"), no_std)]
+#![cfg_attr(feature = "simd", feature(portable_simd))]
+
+extern crate alloc;
+
+use alloc::collections::VecDeque;
+use alloc::{string::String, vec, vec::Vec};
+use core::ops::{Add, AddAssign, Mul};
+#[cfg(feature = "cli")]
+use eyre::WrapErr;
+use rand::distributions::{Distribution, Uniform};
+#[cfg(feature = "cli")]
+use rand::Rng;
+#[cfg(feature = "cli")]
+use rand_chacha::{rand_core::SeedableRng, ChaCha8Rng};
+#[cfg(feature = "std")]
+use std::collections::HashMap;
+#[cfg(feature = "cli")]
+use std::io::{BufRead, BufReader, Read};
+#[cfg(feature = "std")]
+use std::sync::{Arc, Mutex, OnceLock};
 
 pub type Coordinates = (usize, usize);
-type Matrix = Vec<i32>;
-pub type Vector = Vec<i32>;
+type Matrix<T = i32> = Vec<T>;
+pub type Vector<T = i32> = Vec<T>;
+
+/// Element types the generic products accept.
+///
+/// The arithmetic is whatever the type implements, so e.g.
+/// `std::num::Wrapping<i32>` gives wrapping products without a separate mode.
+pub trait Num: Copy + Add<Output = Self> + Mul<Output = Self> + AddAssign {}
+
+impl<T: Copy + Add<Output = T> + Mul<Output = T> + AddAssign> Num for T {}
 
+/// `floor(log2(n))`, computed on integers, so it is exact for every `n`.
+///
+/// Panics if `n == 0`. See `log2_exact` to reject non-powers of two.
 #[inline]
 pub fn log2(n: usize) -> usize {
-    (n as f64).log2().floor() as usize
+    n.ilog2() as usize
+}
+
+/// `true` if `n` is a power of two (`0` is not).
+pub const fn is_power_of_two(n: usize) -> bool {
+    n != 0 && n & (n - 1) == 0
+}
+
+/// `k` if `n == 2^k`, and `None` for every other `n`, including 0.
+///
+/// `log2` rounds down instead, so e.g. `log2(1000) == 9` would give a curve
+/// that misses part of a 1000 x 1000 matrix.
+pub const fn log2_exact(n: usize) -> Option<usize> {
+    if is_power_of_two(n) {
+        Some(n.trailing_zeros() as usize)
+    } else {
+        None
+    }
+}
+
+/// The order `k` of a power-of-two side length `n = 2^k`.
+///
+/// Like `log2_exact`, but panics otherwise; usable in `const` contexts, e.g.
+/// array sizes.
+pub const fn order_of(n: usize) -> usize {
+    match log2_exact(n) {
+        Some(k) => k,
+        None => panic!("n must be a power of two"),
+    }
+}
+
+/// The order of the smallest Hilbert curve covering an `n x n` matrix: exact
+/// for powers of two, and `n` rounded up to one otherwise.
+pub fn padded_order(n: usize) -> usize {
+    match log2_exact(n) {
+        Some(k) => k,
+        None => n.next_power_of_two().trailing_zeros() as usize,
+    }
 }
 
 /// Create a matrix.
 /// note that the representation Vec of Vec is not optimal.
 pub fn make_matrix<R: rand::Rng>(n: usize, low: i32, high: i32, rng: &mut R) -> Matrix {
-    let range = Uniform::new(low, high);
-    (0..(n * n)).map(|_| rng.sample(&range)).collect()
+    make_matrix_with(n, &Uniform::new(low, high), rng)
 }
 
-/// Naive product
+/// Create a matrix with entries drawn from `dist`.
+pub fn make_matrix_with<D: Distribution<i32>, R: rand::Rng>(
+    n: usize,
+    dist: &D,
+    rng: &mut R,
+) -> Matrix {
+    (0..(n * n)).map(|_| rng.sample(dist)).collect()
+}
+
+/// The `n x n` identity matrix: `A * v == v`.
+pub fn identity_matrix(n: usize) -> Matrix {
+    diagonal_matrix(&vec![1; n])
+}
+
+/// The square matrix with `diag` on the diagonal and zeros elsewhere.
+pub fn diagonal_matrix(diag: &[i32]) -> Matrix {
+    let n = diag.len();
+    let mut matrix = vec![0; n * n];
+    for (i, d) in diag.iter().enumerate() {
+        matrix[flat_index(i, i, n)] = *d;
+    }
+    matrix
+}
+
+/// The `n x n` matrix with every entry `val`.
+pub fn constant_matrix(n: usize, val: i32) -> Matrix {
+    vec![val; n * n]
+}
+
+/// Side length of the blocks `matrix_with_locality` correlates.
+pub const LOCALITY_BLOCK: usize = 8;
+
+/// Create a matrix (entries in `1..=10`) with tunable spatial correlation.
+///
+/// Every entry blends an independent random value with a random value shared
+/// by its `LOCALITY_BLOCK x LOCALITY_BLOCK` block: `correlation = 0` is the
+/// same matrix `make_matrix` would draw from `rng`, `correlation = 1` makes
+/// every block constant.
+#[cfg(feature = "std")]
+pub fn matrix_with_locality<R: rand::Rng>(n: usize, correlation: f64, rng: &mut R) -> Matrix {
+    assert!(
+        (0.0..=1.0).contains(&correlation),
+        "correlation must be in [0, 1], got {}",
+        correlation
+    );
+    let (low, high) = (1, 11);
+    let independent = make_matrix(n, low, high, rng);
+    let blocks_per_side = n.div_ceil(LOCALITY_BLOCK);
+    let shared = make_matrix(blocks_per_side, low, high, rng);
+    (0..n * n)
+        .map(|k| {
+            let (i, j) = (k / n, k % n);
+            let block = shared[flat_index(i / LOCALITY_BLOCK, j / LOCALITY_BLOCK, blocks_per_side)];
+            ((1.0 - correlation) * independent[k] as f64 + correlation * block as f64).round()
+                as i32
+        })
+        .collect()
+}
+
+/// Row-major copy of a nested `n x n` matrix.
+///
+/// Panics unless there are `n` rows of length `n`.
 #[allow(non_snake_case)]
-pub fn naive_matrix_vector_product(A: &Matrix, v: &Vector, output: &mut Vector, n: usize) {
-    // // TODO: put asserts here to make sure no bounds checking happens.
-    // assert_eq!(output.len(), n);
-    // assert_eq!(A.len(), n * n);
-    // assert_eq!(v.len(), n);
-    for i in 0..n {
-        for j in 0..n {
-            output[i] += A[flat_index(i, j, n)] * v[j];
+#[track_caller]
+pub fn flatten_nested<T: Copy>(A: &[Vec<T>]) -> Vec<T> {
+    let n = A.len();
+    for (i, row) in A.iter().enumerate() {
+        assert_eq!(
+            row.len(),
+            n,
+            "row {} has {} elements, expected {} for a square matrix",
+            i,
+            row.len(),
+            n
+        );
+    }
+    A.concat()
+}
+
+/// Nested copy of a row-major `n x n` matrix; the inverse of `flatten_nested`.
+#[allow(non_snake_case)]
+#[track_caller]
+pub fn nest_matrix<T: Copy>(A: &[T], n: usize) -> Vec<Vec<T>> {
+    assert_eq!(A.len(), n * n, "expected {} elements for n = {}", n * n, n);
+    A.chunks(n.max(1)).map(|row| row.to_vec()).collect()
+}
+
+/// A row-major `rows x cols` matrix that carries its dimensions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DenseMatrix {
+    data: Vec<i32>,
+    rows: usize,
+    cols: usize,
+}
+
+impl DenseMatrix {
+    /// A square `n x n` matrix.
+    #[track_caller]
+    pub fn new(data: Vec<i32>, n: usize) -> Self {
+        assert_dimensions(n, &data);
+        Self::with_shape(data, n, n)
+    }
+
+    #[track_caller]
+    pub fn with_shape(data: Vec<i32>, rows: usize, cols: usize) -> Self {
+        assert_eq!(
+            data.len(),
+            rows * cols,
+            "expected a {}x{} matrix with {} elements, but got {} elements",
+            rows,
+            cols,
+            rows * cols,
+            data.len()
+        );
+        Self { data, rows, cols }
+    }
+
+    /// Panics unless all rows have the same length.
+    #[track_caller]
+    pub fn from_rows(rows: &[Vec<i32>]) -> Self {
+        let cols = rows.first().map_or(0, Vec::len);
+        for (i, row) in rows.iter().enumerate() {
+            assert_eq!(
+                row.len(),
+                cols,
+                "row {} has {} elements, expected {}",
+                i,
+                row.len(),
+                cols
+            );
+        }
+        Self::with_shape(rows.concat(), rows.len(), cols)
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn cols(&self) -> usize {
+        self.cols
+    }
+
+    /// The side length of a square matrix.
+    #[track_caller]
+    pub fn n(&self) -> usize {
+        assert_eq!(self.rows, self.cols, "the matrix is not square");
+        self.rows
+    }
+
+    /// The entry `(i, j)`, or `None` outside the matrix.
+    pub fn get(&self, i: usize, j: usize) -> Option<i32> {
+        if i < self.rows && j < self.cols {
+            Some(self.data[flat_index(i, j, self.cols)])
+        } else {
+            None
         }
     }
+
+    #[track_caller]
+    pub fn set(&mut self, i: usize, j: usize, value: i32) {
+        assert!(
+            i < self.rows && j < self.cols,
+            "({}, {}) is outside the {}x{} matrix",
+            i,
+            j,
+            self.rows,
+            self.cols
+        );
+        self.data[flat_index(i, j, self.cols)] = value;
+    }
+
+    /// The row-major entries.
+    pub fn as_slice(&self) -> &[i32] {
+        &self.data
+    }
+
+    pub fn into_vec(self) -> Vec<i32> {
+        self.data
+    }
+
+    /// `naive_matrix_vector_product_rect` with the shape taken from the matrix.
+    #[track_caller]
+    pub fn naive_product(&self, v: &[i32]) -> Vec<i32> {
+        assert_eq!(
+            v.len(),
+            self.cols,
+            "expected a vector of length {}",
+            self.cols
+        );
+        let mut output = vec![0; self.rows];
+        naive_matrix_vector_product_rect(&self.data, v, &mut output, self.rows, self.cols);
+        output
+    }
+
+    /// `setup_hilbert_rect` with the shape taken from the matrix.
+    pub fn setup_hilbert(self) -> (Vec<(usize, Coordinates)>, Vec<i32>) {
+        setup_hilbert_rect(self.rows, self.cols, self.data)
+    }
 }
 
-/// Converts [i][j] into [n*i+j]
-#[inline]
-fn flat_index(i: usize, j: usize, n: usize) -> usize {
-    n * i + j
+impl From<(Vec<i32>, usize)> for DenseMatrix {
+    #[track_caller]
+    fn from((data, n): (Vec<i32>, usize)) -> Self {
+        Self::new(data, n)
+    }
 }
 
-/// Flatten matrix A according to the provided Hilbert coordinates.
-#[allow(non_snake_case)]
-pub fn flatten_matrix(depth: usize, A: Vec<i32>, n: usize) -> Vector {
-    let mut flattened_A = vec![0; n * n];
-    for (t, (i, j)) in HilbertIter::new(depth) {
-        flattened_A[t] = A[flat_index(i, j, n)];
+impl From<Vec<Vec<i32>>> for DenseMatrix {
+    #[track_caller]
+    fn from(rows: Vec<Vec<i32>>) -> Self {
+        Self::from_rows(&rows)
+    }
+}
+
+/// Where the entries of an `n x n` matrix live, so the products can run on
+/// other layouts than a row-major slice.
+///
+/// `n` is passed in because a plain slice doesn't know its side length.
+pub trait MatrixStorage<T = i32> {
+    /// The entry `(i, j)`.
+    fn entry(&self, i: usize, j: usize, n: usize) -> T;
+
+    /// How many entries the storage holds, if it holds any; used to check
+    /// the dimensions in debug builds.
+    fn stored_len(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// Row-major, the layout used throughout the crate.
+impl<T: Copy> MatrixStorage<T> for [T] {
+    #[inline]
+    fn entry(&self, i: usize, j: usize, n: usize) -> T {
+        at(self, flat_index(i, j, n), "A")
+    }
+
+    fn stored_len(&self) -> Option<usize> {
+        Some(self.len())
+    }
+}
+
+impl<T: Copy> MatrixStorage<T> for Vec<T> {
+    #[inline]
+    fn entry(&self, i: usize, j: usize, n: usize) -> T {
+        at(self, flat_index(i, j, n), "A")
+    }
+
+    fn stored_len(&self) -> Option<usize> {
+        Some(self.len())
+    }
+}
+
+/// A column-major `n x n` matrix: `(i, j)` is at `j * n + i`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ColumnMajor<'a, T>(pub &'a [T]);
+
+impl<T: Copy> MatrixStorage<T> for ColumnMajor<'_, T> {
+    #[inline]
+    fn entry(&self, i: usize, j: usize, n: usize) -> T {
+        at(self.0, flat_index(j, i, n), "A")
+    }
+
+    fn stored_len(&self) -> Option<usize> {
+        Some(self.0.len())
+    }
+}
+
+/// A matrix whose entries `f(i, j)` are computed on demand, so a product can
+/// run against a formula without materializing `n * n` values.
+#[derive(Debug, Clone, Copy)]
+pub struct FnMatrix<F>(pub F);
+
+impl<T, F: Fn(usize, usize) -> T> MatrixStorage<T> for FnMatrix<F> {
+    #[inline]
+    fn entry(&self, i: usize, j: usize, _n: usize) -> T {
+        (self.0)(i, j)
     }
-    flattened_A
 }
 
+/// Naive product
 #[allow(non_snake_case)]
-pub fn hilbert_matrix_vector_product(
-    flattened_A: &Vector,
-    v: &Vector,
-    output: &mut Vector,
-    hilbert_iter: &Vec<(usize, Coordinates)>,
+pub fn naive_matrix_vector_product<T: Num, M: MatrixStorage<T> + ?Sized>(
+    A: &M,
+    v: &[T],
+    output: &mut [T],
+    n: usize,
 ) {
-    for (t, (i, j)) in hilbert_iter {
-        output[*i] += flattened_A[*t] * v[*j];
+    // Checked in debug builds only, so the release loop stays as it was.
+    if let Some(len) = A.stored_len() {
+        debug_assert_eq!(len, n * n, "A has {} elements, expected {}", len, n * n);
+    }
+    debug_assert_eq!(v.len(), n, "v has {} elements, expected {}", v.len(), n);
+    debug_assert_eq!(
+        output.len(),
+        n,
+        "output has {} elements, expected {}",
+        output.len(),
+        n
+    );
+    for (i, out) in output[..n].iter_mut().enumerate() {
+        for (j, x) in v[..n].iter().enumerate() {
+            *out += A.entry(i, j, n) * *x;
+        }
     }
 }
 
-/// `hilbert_matrix_vector_product` but Hilbert index is an iterator.
+/// Naive product of a row-major `rows x cols` matrix: `v` has `cols`
+/// entries and `output` has `rows`.
 #[allow(non_snake_case)]
-pub fn hilbert_matrix_vector_product_iter(
-    flattened_A: &Vector,
-    v: &Vector,
-    output: &mut Vector,
-    depth: usize,
+pub fn naive_matrix_vector_product_rect<T: Num>(
+    A: &[T],
+    v: &[T],
+    output: &mut [T],
+    rows: usize,
+    cols: usize,
 ) {
-    for (t, (i, j)) in HilbertIter::new(depth) {
-        output[i] += flattened_A[t] * v[j];
+    for i in 0..rows {
+        for j in 0..cols {
+            *at_mut(output, i, "output") += at(A, flat_index(i, j, cols), "A") * at(v, j, "v");
+        }
     }
 }
 
-struct HilbertIter {
-    /// Number of steps remaining
-    n: usize,
-    index: usize,
-    i: usize,
-    j: usize,
-    queue: VecDeque<(char, usize)>,
-    buffer: Option<(usize, Coordinates)>,
+/// `naive_matrix_vector_product` for `i32`, eight columns at a time with
+/// `std::simd`. Requires a nightly compiler.
+#[cfg(feature = "simd")]
+#[allow(non_snake_case)]
+pub fn naive_matrix_vector_product_simd(A: &[i32], v: &[i32], output: &mut [i32], n: usize) {
+    use std::simd::{i32x8, num::SimdInt};
+
+    if n == 0 {
+        return;
+    }
+    let v_lanes = v[..n].chunks_exact(8);
+    let v_tail = v_lanes.remainder();
+    for (out, row) in output.iter_mut().zip(A.chunks_exact(n)) {
+        let row_lanes = row.chunks_exact(8);
+        let row_tail = row_lanes.remainder();
+        let mut sum = i32x8::splat(0);
+        for (a, x) in row_lanes.zip(v_lanes.clone()) {
+            sum += i32x8::from_slice(a) * i32x8::from_slice(x);
+        }
+        *out += sum.reduce_sum();
+        for (a, x) in row_tail.iter().zip(v_tail) {
+            *out += a * x;
+        }
+    }
 }
 
-impl HilbertIter {
-    pub fn new(depth: usize) -> Self {
-        let n = 2usize.pow(depth as u32);
-        let n = n * n + 1;
-        let queue = VecDeque::from([('H', depth)]);
-        Self {
-            n,
-            index: 1,
-            i: 0,
-            j: 0,
-            queue,
-            buffer: Some((0, (0, 0))),
+/// `naive_matrix_vector_product` with `i64` accumulators, for inputs whose
+/// products don't fit `i32`.
+///
+/// An `i32` entry of the output can overflow once `n * max|A| * max|v|`
+/// exceeds `i32::MAX` (see `product_may_overflow`). The `i64` accumulators
+/// only overflow once it exceeds `i64::MAX`, e.g. for `n >= 2^31` with entries
+/// up to `2^16`.
+#[allow(non_snake_case)]
+pub fn naive_matrix_vector_product_checked(A: &[i32], v: &[i32], n: usize) -> Vec<i64> {
+    let mut output = vec![0i64; n];
+    for (i, out) in output.iter_mut().enumerate() {
+        for j in 0..n {
+            *out += A[flat_index(i, j, n)] as i64 * v[j] as i64;
+        }
+    }
+    output
+}
+
+/// Naive product for a nested `Vec<Vec<_>>` matrix.
+///
+/// Each row is bound once outside the inner loop, so only a single bounds
+/// check and no extra pointer chase remain per element.
+#[allow(non_snake_case)]
+pub fn naive_matrix_vector_product_nested<T: Num>(A: &[Vec<T>], v: &[T], output: &mut [T]) {
+    for (out, row) in output.iter_mut().zip(A) {
+        for (a, x) in row.iter().zip(v) {
+            *out += *a * *x;
         }
     }
+}
 
-    fn step(&mut self) {
-        while self.buffer.is_none() && !self.queue.is_empty() {
-            let (symbol, depth) = self.queue.pop_front().unwrap();
-            if depth == 0 {
-                let non_terminal = match symbol {
-                    '↑' => {
-                        self.i += 1;
-                        true
-                    }
-                    '↓' => {
-                        self.i -= 1;
-                        true
-                    }
-                    '→' => {
-                        self.j += 1;
-                        true
-                    }
-                    '←' => {
-                        self.j -= 1;
-                        true
-                    }
-                    _ => false,
-                };
-                if non_terminal {
-                    self.buffer = Some((self.index, (self.i, self.j)));
-                    self.index += 1;
+/// Naive product walking `A` column by column.
+///
+/// Same result as `naive_matrix_vector_product`, but every access to the
+/// row-major `A` jumps by a full row: a worst-case baseline for access order.
+#[allow(non_snake_case)]
+pub fn naive_matrix_vector_product_colmajor(A: &[i32], v: &[i32], output: &mut [i32], n: usize) {
+    for j in 0..n {
+        for i in 0..n {
+            *at_mut(output, i, "output") += at(A, flat_index(i, j, n), "A") * at(v, j, "v");
+        }
+    }
+}
+
+/// Naive product walking `A` in `block x block` tiles, row-major within and
+/// across tiles: the usual cache-blocking baseline for the curves.
+///
+/// `n` doesn't have to be a multiple of `block`; the last tiles are smaller.
+#[allow(non_snake_case)]
+#[track_caller]
+pub fn blocked_matrix_vector_product<T: Num>(
+    A: &[T],
+    v: &[T],
+    output: &mut [T],
+    n: usize,
+    block: usize,
+) {
+    assert!(block > 0, "block must be positive");
+    for i0 in (0..n).step_by(block) {
+        for j0 in (0..n).step_by(block) {
+            for i in i0..(i0 + block).min(n) {
+                for j in j0..(j0 + block).min(n) {
+                    *at_mut(output, i, "output") += at(A, flat_index(i, j, n), "A") * at(v, j, "v");
                 }
             }
-            if depth > 0 {
-                match symbol {
-                    'H' => {
-                        self.queue.push_back(('A', depth - 1));
-                        self.queue.push_back(('↑', depth - 1));
-                        self.queue.push_back(('H', depth - 1));
-                        self.queue.push_back(('→', depth - 1));
-                        self.queue.push_back(('H', depth - 1));
-                        self.queue.push_back(('↓', depth - 1));
-                        self.queue.push_back(('B', depth - 1));
-                    }
-                    'A' => {
-                        self.queue.push_back(('H', depth - 1));
-                        self.queue.push_back(('→', depth - 1));
-                        self.queue.push_back(('A', depth - 1));
-                        self.queue.push_back(('↑', depth - 1));
-                        self.queue.push_back(('A', depth - 1));
-                        self.queue.push_back(('←', depth - 1));
-                        self.queue.push_back(('C', depth - 1));
-                    }
-                    'B' => {
-                        self.queue.push_back(('C', depth - 1));
-                        self.queue.push_back(('←', depth - 1));
-                        self.queue.push_back(('B', depth - 1));
-                        self.queue.push_back(('↓', depth - 1));
-                        self.queue.push_back(('B', depth - 1));
-                        self.queue.push_back(('→', depth - 1));
-                        self.queue.push_back(('H', depth - 1));
-                    }
-                    'C' => {
-                        self.queue.push_back(('B', depth - 1));
-                        self.queue.push_back(('↓', depth - 1));
-                        self.queue.push_back(('C', depth - 1));
-                        self.queue.push_back(('←', depth - 1));
-                        self.queue.push_back(('C', depth - 1));
-                        self.queue.push_back(('↑', depth - 1));
-                        self.queue.push_back(('A', depth - 1));
-                    }
-                    _ => {
-                        // # terminal up/down/left/right symbols
-                        // # must be preserved until the end
-                        self.queue.push_back((symbol, depth - 1));
-                    }
-                };
-            }
         }
     }
 }
 
-impl Iterator for HilbertIter {
-    type Item = (usize, Coordinates);
+/// Converts [i][j] into [n*i+j]
+#[inline]
+fn flat_index(i: usize, j: usize, n: usize) -> usize {
+    n * i + j
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.n > 0 {
-            self.n -= 1;
-        } else {
-            panic!("Hilbert iteration is out of bounds");
-        }
-        self.step();
-        self.buffer.take()
+/// `slice[index]` for the product loops. With the `safe-index` feature an
+/// out-of-bounds index panics with the name of the slice, even in code that
+/// otherwise skips bounds checks.
+#[cfg(not(feature = "safe-index"))]
+#[inline(always)]
+#[track_caller]
+fn at<T: Copy>(slice: &[T], index: usize, _name: &str) -> T {
+    slice[index]
+}
+
+#[cfg(feature = "safe-index")]
+#[inline(always)]
+#[track_caller]
+fn at<T: Copy>(slice: &[T], index: usize, name: &str) -> T {
+    match slice.get(index) {
+        Some(x) => *x,
+        None => out_of_bounds(name, index, slice.len()),
     }
+}
 
-    fn size_hint(&self) -> (usize, Option<usize>) {
-        (self.n, Some(self.n))
+/// `&mut slice[index]`, checked like `at`.
+#[cfg(not(feature = "safe-index"))]
+#[inline(always)]
+#[track_caller]
+fn at_mut<'a, T>(slice: &'a mut [T], index: usize, _name: &str) -> &'a mut T {
+    &mut slice[index]
+}
+
+#[cfg(feature = "safe-index")]
+#[inline(always)]
+#[track_caller]
+fn at_mut<'a, T>(slice: &'a mut [T], index: usize, name: &str) -> &'a mut T {
+    let len = slice.len();
+    match slice.get_mut(index) {
+        Some(x) => x,
+        None => out_of_bounds(name, index, len),
     }
 }
 
-/// Generate (A, v) as inputs for matrix multiplication
-pub fn setup_inputs(n: usize, rng: &mut ChaCha8Rng) -> (Vec<i32>, Vec<i32>) {
-    let range = Uniform::new(1, 11);
+#[cfg(feature = "safe-index")]
+#[cold]
+#[track_caller]
+fn out_of_bounds(name: &str, index: usize, len: usize) -> ! {
+    panic!(
+        "{}[{}] is out of bounds: {} has {} elements",
+        name, index, name, len
+    )
+}
 
-    #[allow(non_snake_case)]
-    let A = make_matrix(n, 1, 11, rng);
-    let v: Vec<_> = (0..n).map(|_| rng.sample(&range)).collect();
-    assert_eq!(v.len(), n);
-    (A, v)
+/// Flatten matrix A according to the provided Hilbert coordinates.
+///
+/// If the curve of order `depth` is larger than the matrix, cells outside of
+/// it are skipped, so the result always has `n * n` entries.
+#[allow(non_snake_case)]
+pub fn flatten_matrix<T: Copy + Default>(depth: usize, A: Vec<T>, n: usize) -> Vector<T> {
+    let coordinate_iter: Vec<_> = HilbertIter::new(depth)
+        .coords()
+        .filter(|(i, j)| *i < n && *j < n)
+        .enumerate()
+        .collect();
+    let mut flattened_A = vec![T::default(); n * n];
+    flatten_matrix_into(&coordinate_iter, &A, &mut flattened_A, n);
+    flattened_A
 }
 
-/// Setup (coordinates, flattened_A) for Hilbert multiplication
+/// The Hilbert order of an `n x n` matrix as a permutation of its row-major
+/// indices: `perm[t] == flat_index(i, j, n)` for the `t`-th cell `(i, j)`.
+///
+/// Gathering by `perm` is `flatten_matrix`: `flattened_A[t] == A[perm[t]]`.
+/// Like `setup_hilbert`, the curve is padded to a power of two and the cells
+/// outside the matrix are skipped.
+pub fn hilbert_permutation(n: usize) -> Vec<usize> {
+    HilbertIter::new(padded_order(n))
+        .coords()
+        .filter(|(i, j)| *i < n && *j < n)
+        .map(|(i, j)| flat_index(i, j, n))
+        .collect()
+}
+
+/// The inverse of `hilbert_permutation(n)`: `inv[k]` is the curve position of
+/// the row-major index `k`, so `inv[perm[t]] == t`.
+pub fn hilbert_permutation_inverse(n: usize) -> Vec<usize> {
+    let mut inverse = vec![0; n * n];
+    for (t, k) in hilbert_permutation(n).into_iter().enumerate() {
+        inverse[k] = t;
+    }
+    inverse
+}
+
+/// Undo `flatten_matrix`: the row-major `n x n` matrix whose Hilbert order is
+/// `flattened`, given `inverse_perm = hilbert_permutation_inverse(n)`.
+#[track_caller]
+pub fn unflatten_matrix<T: Copy>(flattened: &[T], inverse_perm: &[usize], n: usize) -> Vector<T> {
+    assert_eq!(
+        flattened.len(),
+        n * n,
+        "expected {} elements, got {}",
+        n * n,
+        flattened.len()
+    );
+    assert_eq!(
+        inverse_perm.len(),
+        n * n,
+        "expected a permutation of {} elements, got {}",
+        n * n,
+        inverse_perm.len()
+    );
+    inverse_perm.iter().map(|&t| flattened[t]).collect()
+}
+
+/// `flatten_matrix` into a caller-provided buffer, e.g. to flatten many
+/// matrices of the same size without reallocating.
+///
+/// `coordinate_iter` is the curve as returned by `setup_hilbert`.
 #[allow(non_snake_case)]
-pub fn setup_hilbert(n: usize, A: Vec<i32>) -> (Vec<(usize, Coordinates)>, Vec<i32>) {
-    assert_eq!(n * n, A.len());
-    let depth: usize = log2(n);
-    let hilbert_iter: Vec<_> = HilbertIter::new(depth).collect();
-    println!("Hilbert matrix size: {}", hilbert_iter.len());
+pub fn flatten_matrix_into<T: Copy>(
+    coordinate_iter: &[(usize, Coordinates)],
+    A: &[T],
+    out: &mut [T],
+    n: usize,
+) {
+    assert_eq!(
+        out.len(),
+        n * n,
+        "expected an output buffer of {} elements",
+        n * n
+    );
+    for (t, (i, j)) in coordinate_iter {
+        out[*t] = A[flat_index(*i, *j, n)];
+    }
+}
 
-    #[allow(non_snake_case)]
-    let flattened_A = flatten_matrix(depth, A, n);
-    (hilbert_iter, flattened_A)
+/// The Hilbert order as positions into a row-major matrix: `perm[t] == n * i + j`.
+///
+/// This is all a gather-based product needs, without recomputing `flat_index`
+/// in the hot loop. The curve of `order` must cover the matrix exactly, i.e.
+/// `n == 2^order`; it is then `hilbert_permutation(n)`.
+#[track_caller]
+pub fn hilbert_flat_permutation(order: usize, n: usize) -> Vec<usize> {
+    assert_eq!(
+        log2_exact(n),
+        Some(order),
+        "n = {} is not 2^{}, the side of the curve",
+        n,
+        order
+    );
+    hilbert_permutation(n)
 }
-/// Setup (depth, flattened_A) for Hilbert multiplication
+
+/// Flatten matrix A along the Hilbert curve without a coordinate table.
+///
+/// Recurses over the quadrants of the L-system directly, so each quadrant's
+/// data ends up contiguous. Produces the same layout as `flatten_matrix`.
 #[allow(non_snake_case)]
-pub fn setup_hilbert_iter(n: usize, A: Vec<i32>) -> (usize, Vec<i32>) {
-    assert_eq!(n * n, A.len());
-    let depth: usize = log2(n);
+pub fn flatten_matrix_recursive(A: &[i32], n: usize) -> Vector {
+    assert!(n.is_power_of_two(), "n = {} is not a power of two", n);
+    let mut flattened_A = Vec::with_capacity(n * n);
+    visit_quadrants(Symbol::H, (0, 0), n, &mut |i, j| {
+        flattened_A.push(A[flat_index(i, j, n)])
+    });
+    flattened_A
+}
 
-    #[allow(non_snake_case)]
-    let flattened_A = flatten_matrix(depth, A, n);
-    (depth, flattened_A)
+/// `hilbert_matrix_vector_product` recursing over quadrants instead of reading
+/// a coordinate table; `flattened_A` comes from `flatten_matrix_recursive`.
+#[allow(non_snake_case)]
+pub fn hilbert_matrix_vector_product_recursive(
+    flattened_A: &[i32],
+    v: &[i32],
+    output: &mut [i32],
+    n: usize,
+) {
+    assert!(n.is_power_of_two(), "n = {} is not a power of two", n);
+    let mut t = 0;
+    visit_quadrants(Symbol::H, (0, 0), n, &mut |i, j| {
+        *at_mut(output, i, "output") += at(flattened_A, t, "flattened_A") * at(v, j, "v");
+        t += 1;
+    });
 }
 
-#[cfg(test)]
-mod test {
-    use std::time::{self};
+/// Call `f(i, j)` for every cell of the `size x size` block at `corner`, in the
+/// order of the curve produced by the non-terminal `symbol`.
+fn visit_quadrants<F: FnMut(usize, usize)>(
+    symbol: Symbol,
+    corner: Coordinates,
+    size: usize,
+    f: &mut F,
+) {
+    visit_windows(symbol, corner, size, 1, &mut |_, (i, j)| f(i, j));
+}
 
-    use insta::assert_yaml_snapshot;
-    use rand::distributions::Uniform;
-    use rand::Rng;
-    use rand_chacha::{rand_core::SeedableRng, ChaCha8Rng};
-    use timeit::timeit_loops;
+/// Call `f(symbol, corner)` for every `window x window` sub-block of the
+/// `size x size` block at `corner`, in curve order, with the non-terminal
+/// that draws the curve inside it.
+fn visit_windows<F: FnMut(Symbol, Coordinates)>(
+    symbol: Symbol,
+    corner: Coordinates,
+    size: usize,
+    window: usize,
+    f: &mut F,
+) {
+    if size <= window {
+        f(symbol, corner);
+        return;
+    }
+    let half = size / 2;
+    for (qi, qj, sub_symbol) in quadrants(symbol) {
+        visit_windows(
+            sub_symbol,
+            (corner.0 + qi * half, corner.1 + qj * half),
+            half,
+            window,
+            f,
+        );
+    }
+}
 
-    use crate::{
-        hilbert_matrix_vector_product, hilbert_matrix_vector_product_iter, make_matrix,
-        naive_matrix_vector_product,
-    };
+/// Order of the windows `hilbert_matrix_vector_product_windowed` is meant to
+/// be run with: the four `128 x 128` local tables take 256 KiB, which stays in
+/// L2 on current desktop CPUs.
+pub const DEFAULT_WINDOW_ORDER: usize = 7;
 
-    #[test]
-    fn test_equality_of_implementation() {
-        let mut rng = ChaCha8Rng::seed_from_u64(10);
+/// `hilbert_matrix_vector_product` without the `n * n` coordinate table.
+///
+/// The curve of order `depth` is made of `2^window_order`-sided sub-curves,
+/// each drawn by one of the four non-terminals. Their local coordinates are
+/// tabulated once (`4^window_order` cells each) and every window of
+/// `flattened_A` is walked with the table of its non-terminal, offset by the
+/// window's corner. The full table costs 24 bytes per cell on top of the 4 of
+/// `flattened_A`; once it outgrows the caches, reading it dominates the
+/// product (the slowdown first seen at `n = 2^14`). The local tables stay in
+/// cache however large `n` is.
+///
+/// `flattened_A` is the whole curve of order `depth`, so `n = 2^depth`.
+#[allow(non_snake_case)]
+#[track_caller]
+pub fn hilbert_matrix_vector_product_windowed<T: Num>(
+    flattened_A: &[T],
+    v: &[T],
+    output: &mut [T],
+    depth: usize,
+    window_order: usize,
+) {
+    assert_eq!(
+        flattened_A.len(),
+        1 << (2 * depth),
+        "flattened_A must cover the curve of order {}",
+        depth
+    );
+    let window_order = window_order.min(depth);
+    assert!(
+        window_order <= u16::BITS as usize,
+        "window order {} exceeds 16",
+        window_order
+    );
+    let side = 1 << window_order;
+    // Indexed by non-terminal; `H`, `A`, `B`, `C` are the first four variants.
+    let tables = local_curves(side, |i, j| (i as u16, j as u16));
+    let mut windows = flattened_A.chunks_exact(side * side);
+    visit_windows(
+        Symbol::H,
+        (0, 0),
+        1 << depth,
+        side,
+        &mut |symbol, (i0, j0)| {
+            let window = windows.next().unwrap();
+            for (a, (di, dj)) in window.iter().zip(&tables[symbol as usize]) {
+                *at_mut(output, i0 + *di as usize, "output") += *a * at(v, j0 + *dj as usize, "v");
+            }
+        },
+    );
+}
 
-        let range = Uniform::new(1, 11);
-        let n: usize = 2usize.pow(8);
+/// Symbols of the Hilbert L-system: the non-terminals `H`, `A`, `B`, `C`
+/// and the four moves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Symbol {
+    H,
+    A,
+    B,
+    C,
+    Up,
+    Down,
+    Left,
+    Right,
+}
 
-        let start = time::Instant::now();
-        // A = [[random.randint(1, 10) for _ in range(n)] for _ in range(n)]
-        #[allow(non_snake_case)]
-        let A = make_matrix(n, 1, 11, &mut rng);
-        // v = [random.randint(1, 10) for _ in range(n)]
-        let v: Vec<_> = (0..n).map(|_| rng.sample(&range)).collect();
-        assert_eq!(v.len(), n);
-        let mut output1 = vec![0; n];
+/// The quadrants a non-terminal of the L-system visits, in curve order, as
+/// `(row half, column half, non-terminal of that quadrant)`.
+const fn quadrants(symbol: Symbol) -> [(usize, usize, Symbol); 4] {
+    use Symbol::*;
+    match symbol {
+        H => [(0, 0, A), (1, 0, H), (1, 1, H), (0, 1, B)],
+        A => [(0, 0, H), (0, 1, A), (1, 1, A), (1, 0, C)],
+        B => [(1, 1, C), (1, 0, B), (0, 0, B), (0, 1, H)],
+        C => [(1, 1, B), (0, 1, C), (0, 0, C), (1, 0, A)],
+        Up | Down | Left | Right => panic!("moves are not non-terminals"),
+    }
+}
+
+/// Index of the cell `(x, y)` (row, column) on the Hilbert curve of `order`,
+/// in O(order) without generating the curve.
+///
+/// This is the usual bit-by-bit descent, with the rotations of the textbook
+/// version replaced by the grammar's `quadrants` table, so the orientation
+/// matches `HilbertIter` exactly.
+pub fn xy_to_hilbert(x: usize, y: usize, order: usize) -> usize {
+    debug_assert!(
+        x >> order == 0 && y >> order == 0,
+        "({}, {}) is outside the grid",
+        x,
+        y
+    );
+    let mut symbol = Symbol::H;
+    let mut d = 0;
+    for level in (0..order).rev() {
+        let cell = ((x >> level) & 1, (y >> level) & 1);
+        let (q, (_, _, sub_symbol)) = quadrants(symbol)
+            .into_iter()
+            .enumerate()
+            .find(|(_, (qi, qj, _))| (*qi, *qj) == cell)
+            .unwrap();
+        d = 4 * d + q;
+        symbol = sub_symbol;
+    }
+    d
+}
+
+/// `xy_to_hilbert` by the rotation algorithm (`xy2d`) from Wikipedia's
+/// "Hilbert curve" article, which knows nothing about the grammar. It serves
+/// as an independent reference for `HilbertIter`.
+pub fn xy_to_hilbert_graycode(x: usize, y: usize, order: usize) -> usize {
+    let n = 1 << order;
+    // Wikipedia's `x` is the column and `y` the row.
+    let (mut x, mut y) = (y, x);
+    let mut d = 0;
+    let mut s = n / 2;
+    while s > 0 {
+        let rx = usize::from(x & s > 0);
+        let ry = usize::from(y & s > 0);
+        d += s * s * ((3 * rx) ^ ry);
+        // Rotate the quadrant back to the orientation of the whole curve.
+        if ry == 0 {
+            if rx == 1 {
+                x = n - 1 - x;
+                y = n - 1 - y;
+            }
+            core::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    d
+}
+
+/// Cell `(i, j)` at index `d` on the Hilbert curve of `order`; the inverse of
+/// `xy_to_hilbert`.
+pub const fn hilbert_to_xy(d: usize, order: usize) -> Coordinates {
+    debug_assert!(
+        d >> (2 * order) == 0,
+        "the index is past the end of the curve"
+    );
+    descend(d, order).0
+}
+
+/// The cell at index `d` on the curve of `order`, with the non-terminal the
+/// descent ends on: the orientation of the sub-curve that would continue
+/// inside that cell.
+const fn descend(d: usize, order: usize) -> (Coordinates, Symbol) {
+    let mut symbol = Symbol::H;
+    let (mut i, mut j) = (0, 0);
+    // A `while` loop, so the lookup tables can be built at compile time.
+    let mut level = order;
+    while level > 0 {
+        level -= 1;
+        let (qi, qj, sub_symbol) = quadrants(symbol)[(d >> (2 * level)) & 3];
+        i = 2 * i + qi;
+        j = 2 * j + qj;
+        symbol = sub_symbol;
+    }
+    ((i, j), symbol)
+}
+
+/// The `side x side` curve drawn by each of the non-terminals `H`, `A`, `B`,
+/// `C` (in that order), as `cell(i, j)`.
+fn local_curves<C>(side: usize, cell: impl Fn(usize, usize) -> C) -> [Vec<C>; 4] {
+    [Symbol::H, Symbol::A, Symbol::B, Symbol::C].map(|symbol| {
+        let mut curve = Vec::with_capacity(side * side);
+        visit_quadrants(symbol, (0, 0), side, &mut |i, j| curve.push(cell(i, j)));
+        curve
+    })
+}
+
+/// The curve of order `depth` in consecutive blocks of `4^block_order` cells.
+///
+/// Every block is the Hilbert curve of a `2^block_order`-sided tile, in one
+/// of four orientations, so the product can be run tile by tile. Chained
+/// together the blocks are `HilbertIter::new(depth)`.
+pub fn hilbert_blocks(
+    depth: usize,
+    block_order: usize,
+) -> impl Iterator<Item = Vec<(usize, Coordinates)>> {
+    let block_order = block_order.min(depth);
+    let side = 1 << block_order;
+    let curves = local_curves(side, |i, j| (i, j));
+    let tiles_order = depth - block_order;
+    (0..1usize << (2 * tiles_order)).map(move |tile| {
+        let ((ti, tj), symbol) = descend(tile, tiles_order);
+        let start = tile * side * side;
+        curves[symbol as usize]
+            .iter()
+            .enumerate()
+            .map(|(k, (di, dj))| (start + k, (ti * side + di, tj * side + dj)))
+            .collect()
+    })
+}
+
+/// The smallest box `(min, max)` containing the cells at indices
+/// `start..=end` of the Hilbert curve of `order`.
+///
+/// The range is split into aligned runs of `4^k` indices, each of which fills
+/// a `2^k x 2^k` square, so this takes `O(order)` conversions per run rather
+/// than one per index.
+#[track_caller]
+pub fn hilbert_range_bbox(start: usize, end: usize, order: usize) -> (Coordinates, Coordinates) {
+    assert!(start <= end, "the range {start}..={end} is empty");
+    assert!(
+        end >> (2 * order) == 0,
+        "the index {end} is past the end of the curve"
+    );
+    let (mut min, mut max) = ((usize::MAX, usize::MAX), (0, 0));
+    let mut d = start;
+    while d <= end {
+        let mut k = 0;
+        while k < order
+            && d.is_multiple_of(1 << (2 * (k + 1)))
+            && d + (1 << (2 * (k + 1))) - 1 <= end
+        {
+            k += 1;
+        }
+        let (i, j) = hilbert_to_xy(d, order);
+        let (i, j) = (i >> k << k, j >> k << k);
+        let side = (1 << k) - 1;
+        min = (min.0.min(i), min.1.min(j));
+        max = (max.0.max(i + side), max.1.max(j + side));
+        d += 1 << (2 * k);
+    }
+    (min, max)
+}
+
+/// Largest order `xy_to_hilbert_lut` and `hilbert_to_xy_lut` answer from
+/// `HILBERT_LUT`. The tables store `u16`s, so it can be raised up to 8, at the
+/// cost of `4^LUT_MAX_ORDER` entries per order and direction.
+pub const LUT_MAX_ORDER: usize = 4;
+
+const LUT_LEN: usize = 1 << (2 * LUT_MAX_ORDER);
+
+/// The Hilbert curve of one order as tables in both directions. Only the
+/// first `4^order` entries are used.
+pub struct HilbertLut {
+    /// `to_xy[d]` is the cell at index `d`.
+    pub to_xy: [(u16, u16); LUT_LEN],
+    /// `to_index[flat_index(i, j, 2^order)]` is the index of the cell `(i, j)`.
+    pub to_index: [u16; LUT_LEN],
+}
+
+/// `HILBERT_LUT[order]` for every order up to `LUT_MAX_ORDER`, built at
+/// compile time.
+pub static HILBERT_LUT: [HilbertLut; LUT_MAX_ORDER + 1] = build_luts();
+
+const fn build_luts() -> [HilbertLut; LUT_MAX_ORDER + 1] {
+    const EMPTY: HilbertLut = HilbertLut {
+        to_xy: [(0, 0); LUT_LEN],
+        to_index: [0; LUT_LEN],
+    };
+    let mut luts = [EMPTY; LUT_MAX_ORDER + 1];
+    let mut order = 0;
+    while order <= LUT_MAX_ORDER {
+        let mut d = 0;
+        while d < 1 << (2 * order) {
+            let (i, j) = hilbert_to_xy(d, order);
+            luts[order].to_xy[d] = (i as u16, j as u16);
+            luts[order].to_index[(i << order) + j] = d as u16;
+            d += 1;
+        }
+        order += 1;
+    }
+    luts
+}
+
+/// `xy_to_hilbert` from `HILBERT_LUT` for `order <= LUT_MAX_ORDER`.
+pub fn xy_to_hilbert_lut(x: usize, y: usize, order: usize) -> usize {
+    if order <= LUT_MAX_ORDER {
+        // The tables are padded to `LUT_LEN`, so a cell outside the grid
+        // would silently read another cell's index.
+        debug_assert!(
+            x >> order == 0 && y >> order == 0,
+            "({}, {}) is outside the grid",
+            x,
+            y
+        );
+        HILBERT_LUT[order].to_index[flat_index(x, y, 1 << order)] as usize
+    } else {
+        xy_to_hilbert(x, y, order)
+    }
+}
+
+/// `hilbert_to_xy` from `HILBERT_LUT` for `order <= LUT_MAX_ORDER`.
+pub fn hilbert_to_xy_lut(d: usize, order: usize) -> Coordinates {
+    if order <= LUT_MAX_ORDER {
+        debug_assert!(
+            d >> (2 * order) == 0,
+            "the index is past the end of the curve"
+        );
+        let (i, j) = HILBERT_LUT[order].to_xy[d];
+        (i as usize, j as usize)
+    } else {
+        hilbert_to_xy(d, order)
+    }
+}
+
+/// `xy_to_hilbert` for JavaScript. `order` is at most 15, so the index fits a
+/// `u32`.
+#[cfg(feature = "wasm")]
+#[wasm_bindgen::prelude::wasm_bindgen]
+pub fn wasm_xy_to_hilbert(x: u32, y: u32, order: u32) -> u32 {
+    assert!(order <= 15, "order {} is too large for u32 indices", order);
+    xy_to_hilbert(x as usize, y as usize, order as usize) as u32
+}
+
+/// The Hilbert curve of `order` for JavaScript, as flattened `(row, column)`
+/// pairs: `[i0, j0, i1, j1, ...]`.
+#[cfg(feature = "wasm")]
+#[wasm_bindgen::prelude::wasm_bindgen]
+pub fn wasm_curve_points(order: u32) -> Vec<u32> {
+    HilbertIter::new(order as usize)
+        .coords()
+        .flat_map(|(i, j)| [i as u32, j as u32])
+        .collect()
+}
+
+/// Permutation taking a Hilbert-flattened matrix to its Hilbert-flattened transpose.
+///
+/// Transposing swaps `(i, j)`, so `Aᵀ` in curve order is a reordering of
+/// `flattened_A`: `flattened_At[t] == flattened_A[perm[t]]`.
+pub fn transpose_permutation(coordinate_iter: &[(usize, Coordinates)], n: usize) -> Vec<usize> {
+    let mut position = vec![0; n * n];
+    for (t, (i, j)) in coordinate_iter {
+        position[flat_index(*i, *j, n)] = *t;
+    }
+    let mut perm = vec![0; n * n];
+    for (t, (i, j)) in coordinate_iter {
+        perm[*t] = position[flat_index(*j, *i, n)];
+    }
+    perm
+}
+
+#[allow(non_snake_case)]
+pub fn hilbert_matrix_vector_product<T: Num>(
+    flattened_A: &[T],
+    v: &[T],
+    output: &mut [T],
+    hilbert_iter: &[(usize, Coordinates)],
+) {
+    for (t, (i, j)) in hilbert_iter {
+        *at_mut(output, *i, "output") += at(flattened_A, *t, "flattened_A") * at(v, *j, "v");
+    }
+}
+
+/// Naive product of two row-major `n x n` matrices, accumulated into the
+/// row-major `output`.
+#[allow(non_snake_case)]
+pub fn naive_matrix_matrix_product<T: Num>(A: &[T], B: &[T], output: &mut [T], n: usize) {
+    for i in 0..n {
+        for k in 0..n {
+            for j in 0..n {
+                *at_mut(output, flat_index(i, k, n), "output") +=
+                    at(A, flat_index(i, j, n), "A") * at(B, flat_index(j, k, n), "B");
+            }
+        }
+    }
+}
+
+/// Side of the tiles `hilbert_matrix_matrix_product` multiplies; a power of
+/// two, so that a tile is a contiguous run of the curve.
+const MATRIX_TILE: usize = 32;
+
+/// Product of two matrices flattened along `hilbert_iter`, accumulated into
+/// `output` in the same layout.
+///
+/// Every aligned `2^k x 2^k` square is a contiguous run of the curve, so the
+/// matrices are split into `MATRIX_TILE x MATRIX_TILE` tiles, each stored
+/// row-major once up front. The output tiles are then computed in curve
+/// order, so consecutive tiles share a tile row of `A` or a tile column of
+/// `B`, and within a tile the inner products read contiguous rows.
+///
+/// `hilbert_iter` must cover the whole `n x n` matrix with `n` a power of
+/// two, i.e. come from `setup_hilbert(n, ..)`.
+#[allow(non_snake_case)]
+#[track_caller]
+pub fn hilbert_matrix_matrix_product<T: Num>(
+    flattened_A: &[T],
+    flattened_B: &[T],
+    output: &mut [T],
+    hilbert_iter: &[(usize, Coordinates)],
+) {
+    let n = hilbert_iter.len().isqrt();
+    assert!(
+        n * n == hilbert_iter.len() && is_power_of_two(n),
+        "the curve must cover a 2^k x 2^k matrix, got {} cells",
+        hilbert_iter.len()
+    );
+    let tile = n.min(MATRIX_TILE);
+    let area = tile * tile;
+    let tiles = n / tile;
+    // Tile `s` is `[s * area, (s + 1) * area)` of the curve. `local[t]` is
+    // where cell `t` goes with its tile stored row-major.
+    let mut local = vec![0; n * n];
+    let mut tile_coordinates = vec![(0, 0); tiles * tiles];
+    for &(t, (i, j)) in hilbert_iter {
+        local[t] = t - t % area + flat_index(i % tile, j % tile, tile);
+        tile_coordinates[t / area] = (i / tile, j / tile);
+    }
+    let mut tile_position = vec![0; tiles * tiles];
+    for (s, &(i, j)) in tile_coordinates.iter().enumerate() {
+        tile_position[flat_index(i, j, tiles)] = s;
+    }
+    let mut A = flattened_A.to_vec();
+    let mut B = flattened_B.to_vec();
+    for (t, &l) in local.iter().enumerate() {
+        A[l] = at(flattened_A, t, "flattened_A");
+        B[l] = at(flattened_B, t, "flattened_B");
+    }
+
+    let mut product = output[..area].to_vec();
+    for (s, &(ti, tk)) in tile_coordinates.iter().enumerate() {
+        let cells = s * area..(s + 1) * area;
+        for t in cells.clone() {
+            product[local[t] - s * area] = at(output, t, "output");
+        }
+        for tj in 0..tiles {
+            let a = &A[tile_position[flat_index(ti, tj, tiles)] * area..][..area];
+            let b = &B[tile_position[flat_index(tj, tk, tiles)] * area..][..area];
+            for (product_row, a_row) in product.chunks_exact_mut(tile).zip(a.chunks_exact(tile)) {
+                for (&x, b_row) in a_row.iter().zip(b.chunks_exact(tile)) {
+                    for (y, &z) in product_row.iter_mut().zip(b_row) {
+                        *y += x * z;
+                    }
+                }
+            }
+        }
+        for t in cells {
+            *at_mut(output, t, "output") = product[local[t] - s * area];
+        }
+    }
+}
+
+/// Transpose of the row-major `n x n` matrix `A`, walking it row by row.
+#[allow(non_snake_case)]
+pub fn naive_transpose(A: &[i32], n: usize) -> Vec<i32> {
+    let mut transposed = vec![0; n * n];
+    for i in 0..n {
+        for j in 0..n {
+            transposed[flat_index(j, i, n)] = A[flat_index(i, j, n)];
+        }
+    }
+    transposed
+}
+
+/// Transpose of the row-major `n x n` matrix `A`, visiting the cells in the
+/// order of `coordinate_iter`.
+///
+/// The naive walk reads rows but writes columns; along the Hilbert curve both
+/// the reads and the writes stay within a small block for a while.
+/// `coordinate_iter` must cover every cell, e.g. come from `setup_hilbert`.
+#[allow(non_snake_case)]
+pub fn hilbert_transpose(
+    A: &[i32],
+    n: usize,
+    coordinate_iter: &[(usize, Coordinates)],
+) -> Vec<i32> {
+    let mut transposed = vec![0; n * n];
+    for (_, (i, j)) in coordinate_iter {
+        transposed[flat_index(*j, *i, n)] = A[flat_index(*i, *j, n)];
+    }
+    transposed
+}
+
+/// `hilbert_matrix_vector_product` for a curve stored without `t`: the
+/// `t`-th coordinate belongs to `flattened_A[t]`, which is read sequentially.
+///
+/// This holds for everything `setup_hilbert` returns and saves a third of
+/// the curve's memory.
+#[allow(non_snake_case)]
+pub fn hilbert_matrix_vector_product_dense<T: Num>(
+    flattened_A: &[T],
+    v: &[T],
+    output: &mut [T],
+    coordinates: &[Coordinates],
+) {
+    for (a, (i, j)) in flattened_A.iter().zip(coordinates) {
+        *at_mut(output, *i, "output") += *a * at(v, *j, "v");
+    }
+}
+
+/// The coordinates of a curve as struct-of-arrays with `u32` indices: 12
+/// instead of 24 bytes per cell, enough for `n <= 2^16`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct CoordinateTable {
+    pub t: Vec<u32>,
+    pub i: Vec<u32>,
+    pub j: Vec<u32>,
+}
+
+impl CoordinateTable {
+    pub fn len(&self) -> usize {
+        self.t.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.t.is_empty()
+    }
+}
+
+impl From<&[(usize, Coordinates)]> for CoordinateTable {
+    /// Panics if an index doesn't fit `u32`.
+    #[track_caller]
+    fn from(coordinate_iter: &[(usize, Coordinates)]) -> Self {
+        let narrow = |x: usize| u32::try_from(x).expect("index does not fit u32");
+        let mut table = Self {
+            t: Vec::with_capacity(coordinate_iter.len()),
+            i: Vec::with_capacity(coordinate_iter.len()),
+            j: Vec::with_capacity(coordinate_iter.len()),
+        };
+        for (t, (i, j)) in coordinate_iter {
+            table.t.push(narrow(*t));
+            table.i.push(narrow(*i));
+            table.j.push(narrow(*j));
+        }
+        table
+    }
+}
+
+/// `hilbert_matrix_vector_product` reading the curve from a `CoordinateTable`.
+#[allow(non_snake_case)]
+pub fn hilbert_matrix_vector_product_soa<T: Num>(
+    flattened_A: &[T],
+    v: &[T],
+    output: &mut [T],
+    table: &CoordinateTable,
+) {
+    for ((t, i), j) in table.t.iter().zip(&table.i).zip(&table.j) {
+        *at_mut(output, *i as usize, "output") +=
+            at(flattened_A, *t as usize, "flattened_A") * at(v, *j as usize, "v");
+    }
+}
+
+/// `hilbert_matrix_vector_product` on the rayon thread pool.
+///
+/// Several curve positions write to the same row, so each task accumulates
+/// its chunk of the curve into a private output vector and the partial
+/// outputs are summed afterwards.
+#[cfg(feature = "rayon")]
+#[allow(non_snake_case)]
+pub fn hilbert_matrix_vector_product_parallel<T: Num + Default + Send + Sync>(
+    flattened_A: &[T],
+    v: &[T],
+    output: &mut [T],
+    hilbert_iter: &[(usize, Coordinates)],
+) {
+    use rayon::prelude::*;
+
+    let n = output.len();
+    let chunk_size = (hilbert_iter.len() / rayon::current_num_threads()).max(1);
+    let partial = hilbert_iter
+        .par_chunks(chunk_size)
+        .map(|chunk| {
+            let mut partial = vec![T::default(); n];
+            hilbert_matrix_vector_product(flattened_A, v, &mut partial, chunk);
+            partial
+        })
+        .reduce(
+            || vec![T::default(); n],
+            |mut a, b| {
+                for (x, y) in a.iter_mut().zip(b) {
+                    *x += y;
+                }
+                a
+            },
+        );
+    for (x, y) in output.iter_mut().zip(partial) {
+        *x += y;
+    }
+}
+
+/// `HilbertIter::new(depth).collect()` on the rayon thread pool.
+///
+/// Every cell comes from `hilbert_to_xy` directly, so the index space is split
+/// into one chunk per thread with no grammar state carried between them.
+#[cfg(feature = "rayon")]
+#[track_caller]
+pub fn hilbert_coordinates_parallel(depth: usize) -> Vec<(usize, Coordinates)> {
+    use rayon::prelude::*;
+
+    assert!(
+        depth <= MAX_DEPTH,
+        "depth {} exceeds MAX_DEPTH = {}",
+        depth,
+        MAX_DEPTH
+    );
+    let len = 1 << (2 * depth);
+    let chunk_size = (len / rayon::current_num_threads()).max(1);
+    let mut table = vec![(0, (0, 0)); len];
+    table
+        .par_chunks_mut(chunk_size)
+        .enumerate()
+        .for_each(|(chunk, cells)| {
+            let start = chunk * chunk_size;
+            for (t, cell) in (start..).zip(cells) {
+                *cell = (t, hilbert_to_xy(t, depth));
+            }
+        });
+    table
+}
+
+/// `hilbert_matrix_vector_product` without bounds checks in the inner loop.
+///
+/// # Safety
+///
+/// Every `(t, (i, j))` in `hilbert_iter` must satisfy `t < flattened_A.len()`,
+/// `i < output.len()` and `j < v.len()`. This holds for the coordinates from
+/// `setup_hilbert(n, A)` with `v` and `output` of length `n`; debug builds
+/// check it per term, and with the `safe-index` feature every access is
+/// checked.
+#[allow(non_snake_case)]
+pub unsafe fn hilbert_matrix_vector_product_unchecked<T: Num>(
+    flattened_A: &[T],
+    v: &[T],
+    output: &mut [T],
+    hilbert_iter: &[(usize, Coordinates)],
+) {
+    assert_eq!(flattened_A.len(), hilbert_iter.len());
+    assert_eq!(v.len(), output.len());
+    // With `safe-index` the checked product stands in, so a bad curve panics
+    // instead of being undefined behaviour.
+    #[cfg(feature = "safe-index")]
+    hilbert_matrix_vector_product(flattened_A, v, output, hilbert_iter);
+    #[cfg(not(feature = "safe-index"))]
+    {
+        let a = flattened_A.as_ptr();
+        let x = v.as_ptr();
+        let y = output.as_mut_ptr();
+        for (t, (i, j)) in hilbert_iter {
+            debug_assert!(*t < flattened_A.len() && *i < output.len() && *j < v.len());
+            *y.add(*i) += *a.add(*t) * *x.add(*j);
+        }
+    }
+}
+
+/// Flatten `A` and `B` along the same curve in a single pass.
+#[allow(non_snake_case)]
+pub fn flatten_two_matrices(
+    A: &[i32],
+    B: &[i32],
+    coordinate_iter: &[(usize, Coordinates)],
+    n: usize,
+) -> (Vec<i32>, Vec<i32>) {
+    let mut flattened_A = vec![0; n * n];
+    let mut flattened_B = vec![0; n * n];
+    for (t, (i, j)) in coordinate_iter {
+        let k = flat_index(*i, *j, n);
+        flattened_A[*t] = A[k];
+        flattened_B[*t] = B[k];
+    }
+    (flattened_A, flattened_B)
+}
+
+/// `(A + B) * v` with both matrices flattened by `flatten_two_matrices`,
+/// visiting each curve position once.
+#[allow(non_snake_case)]
+pub fn hilbert_sum_product(
+    flattened_A: &[i32],
+    flattened_B: &[i32],
+    v: &[i32],
+    output: &mut [i32],
+    hilbert_iter: &[(usize, Coordinates)],
+) {
+    for (t, (i, j)) in hilbert_iter {
+        *at_mut(output, *i, "output") += (at(flattened_A, *t, "flattened_A")
+            + at(flattened_B, *t, "flattened_B"))
+            * at(v, *j, "v");
+    }
+}
+
+/// Position-weighted checksum `sum((k + 1) * output[k])`, wrapping in `u64`.
+///
+/// The weights make it sensitive to swapped entries, and since it is linear
+/// it can be accumulated term by term (see `hilbert_product_with_checksum`).
+pub fn vector_checksum(output: &[i32]) -> u64 {
+    output.iter().enumerate().fold(0u64, |sum, (k, x)| {
+        sum.wrapping_add((k as u64 + 1).wrapping_mul(*x as i64 as u64))
+    })
+}
+
+/// `hilbert_matrix_vector_product` that also returns `vector_checksum` of the
+/// result, accumulated alongside the product instead of in a second pass.
+#[allow(non_snake_case)]
+pub fn hilbert_product_with_checksum(
+    flattened_A: &[i32],
+    v: &[i32],
+    hilbert_iter: &[(usize, Coordinates)],
+) -> (Vec<i32>, u64) {
+    let mut output = vec![0; v.len()];
+    let mut checksum = 0u64;
+    for (t, (i, j)) in hilbert_iter {
+        let term = at(flattened_A, *t, "flattened_A") * at(v, *j, "v");
+        *at_mut(&mut output, *i, "output") += term;
+        checksum = checksum.wrapping_add((*i as u64 + 1).wrapping_mul(term as i64 as u64));
+    }
+    (output, checksum)
+}
+
+/// Fold every row of a Hilbert-flattened matrix, e.g. into row sums or norms.
+///
+/// Elements are visited in curve order, so `f` should not depend on the order
+/// of the elements within a row.
+#[allow(non_snake_case)]
+pub fn hilbert_reduce_rows<Acc: Copy, F: Fn(Acc, i32) -> Acc>(
+    flattened_A: &[i32],
+    hilbert_iter: &[(usize, Coordinates)],
+    n: usize,
+    init: Acc,
+    f: F,
+) -> Vec<Acc> {
+    let mut rows = vec![init; n];
+    for (t, (i, _)) in hilbert_iter {
+        rows[*i] = f(rows[*i], flattened_A[*t]);
+    }
+    rows
+}
+
+/// `hilbert_matrix_vector_product` restricted to the output rows `r0..r1`.
+///
+/// `output` holds just those rows (`output[0]` is row `r0`). The curve
+/// interleaves rows, so the whole table is still scanned.
+#[allow(non_snake_case)]
+pub fn hilbert_product_rows(
+    flattened_A: &[i32],
+    v: &[i32],
+    output: &mut [i32],
+    hilbert_iter: &[(usize, Coordinates)],
+    r0: usize,
+    r1: usize,
+) {
+    assert_eq!(
+        output.len(),
+        r1 - r0,
+        "output must hold rows {}..{}",
+        r0,
+        r1
+    );
+    for (t, (i, j)) in hilbert_iter {
+        if (r0..r1).contains(i) {
+            *at_mut(output, *i - r0, "output") +=
+                at(flattened_A, *t, "flattened_A") * at(v, *j, "v");
+        }
+    }
+}
+
+/// `hilbert_matrix_vector_product` but Hilbert index is an iterator.
+///
+/// The curve is regenerated on every call instead of being read from a
+/// precomputed `coordinate_iter`, trading computation for the memory of
+/// `n * n` coordinates. Use `setup_hilbert_iter` to flatten `A`.
+#[allow(non_snake_case)]
+pub fn hilbert_matrix_vector_product_iter<T: Num>(
+    flattened_A: &[T],
+    v: &[T],
+    output: &mut [T],
+    depth: usize,
+) {
+    for (t, (i, j)) in HilbertIter::new(depth) {
+        *at_mut(output, i, "output") += at(flattened_A, t, "flattened_A") * at(v, j, "v");
+    }
+}
+
+/// Deepest curve `HilbertIter` accepts: its `4^depth` indices have to fit a
+/// `usize`, so 31 on 64-bit and 15 on 32-bit targets.
+///
+/// Memory runs out long before that: the grammar is expanded level by level,
+/// so the queue holds `O(4^depth)` symbols (about 3 GB at depth 13).
+pub const MAX_DEPTH: usize = (usize::BITS as usize - 1) / 2;
+
+/// `hilbert_matrix_vector_product_iter` on the row-major `A`: the cells are
+/// visited in curve order but read in place, so no `flattened_A` copy is
+/// needed.
+///
+/// This halves the memory at the cost of the sequential reads of `A`, which
+/// now jump around within each block of the curve. `A` must be
+/// `2^depth x 2^depth`.
+#[allow(non_snake_case)]
+pub fn hilbert_matrix_vector_product_streaming<T: Num>(
+    A: &[T],
+    v: &[T],
+    output: &mut [T],
+    depth: usize,
+) {
+    let n = 1 << depth;
+    for (i, j) in HilbertIter::new(depth).coords() {
+        *at_mut(output, i, "output") += at(A, flat_index(i, j, n), "A") * at(v, j, "v");
+    }
+}
+
+/// Lazily walks the Hilbert curve of order `depth`, yielding `(t, (i, j))`.
+pub struct HilbertIter {
+    /// Number of points still to be yielded.
+    remaining: usize,
+    index: usize,
+    i: usize,
+    j: usize,
+    queue: VecDeque<(Symbol, usize)>,
+    buffer: Option<(usize, Coordinates)>,
+    /// Order and offset of the curve, for jumping to a cell with `hilbert_to_xy`.
+    depth: usize,
+    offset: Coordinates,
+    /// One past the index `next_back` yields next.
+    back: usize,
+}
+
+impl HilbertIter {
+    /// Panics if `depth > MAX_DEPTH`.
+    #[track_caller]
+    pub fn new(depth: usize) -> Self {
+        Self::with_offset(depth, (0, 0))
+    }
+
+    /// Like `new`, but every yielded coordinate is translated by `offset`,
+    /// e.g. to lay several Hilbert-ordered tiles into a larger grid. The
+    /// indices `t` still start at 0.
+    ///
+    /// Coordinates are `usize` throughout the crate, so the offset is too:
+    /// the translated grid has to stay in the non-negative quadrant.
+    #[track_caller]
+    pub fn with_offset(depth: usize, offset: Coordinates) -> Self {
+        assert!(
+            depth <= MAX_DEPTH,
+            "depth {} exceeds MAX_DEPTH = {}",
+            depth,
+            MAX_DEPTH
+        );
+        let queue = VecDeque::from([(Symbol::H, depth)]);
+        Self {
+            remaining: 1 << (2 * depth),
+            depth,
+            offset,
+            back: 1 << (2 * depth),
+            index: 1,
+            i: offset.0,
+            j: offset.1,
+            queue,
+            // The grammar only emits moves, so the starting cell is yielded
+            // up front. For `depth == 0` it is the only cell: the axiom `H` is
+            // consumed without emitting anything.
+            buffer: Some((0, offset)),
+        }
+    }
+
+    /// The cells without their index `t`, e.g.
+    /// `for (i, j) in HilbertIter::new(order).coords()`.
+    pub fn coords(self) -> impl DoubleEndedIterator<Item = Coordinates> + ExactSizeIterator {
+        self.map(|(_, ij)| ij)
+    }
+
+    fn step(&mut self) {
+        use Symbol::*;
+        while self.buffer.is_none() && !self.queue.is_empty() {
+            let (symbol, depth) = self.queue.pop_front().unwrap();
+            if depth == 0 {
+                let moved = match symbol {
+                    Up => {
+                        self.i += 1;
+                        true
+                    }
+                    Down => {
+                        debug_assert!(self.i > self.offset.0, "the curve left the grid");
+                        self.i -= 1;
+                        true
+                    }
+                    Right => {
+                        self.j += 1;
+                        true
+                    }
+                    Left => {
+                        debug_assert!(self.j > self.offset.1, "the curve left the grid");
+                        self.j -= 1;
+                        true
+                    }
+                    // Non-terminals at depth 0 expand to nothing.
+                    H | A | B | C => false,
+                };
+                if moved {
+                    self.buffer = Some((self.index, (self.i, self.j)));
+                    self.index += 1;
+                }
+            }
+            if depth > 0 {
+                let expansion = match symbol {
+                    H => [A, Up, H, Right, H, Down, B],
+                    A => [H, Right, A, Up, A, Left, C],
+                    B => [C, Left, B, Down, B, Right, H],
+                    C => [B, Down, C, Left, C, Up, A],
+                    Up | Down | Left | Right => {
+                        // # terminal up/down/left/right symbols
+                        // # must be preserved until the end
+                        self.queue.push_back((symbol, depth - 1));
+                        continue;
+                    }
+                };
+                self.queue
+                    .extend(expansion.into_iter().map(|symbol| (symbol, depth - 1)));
+            }
+        }
+    }
+}
+
+impl Iterator for HilbertIter {
+    type Item = (usize, Coordinates);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        self.step();
+        self.buffer.take()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+
+    /// Jumps straight to the end of the curve instead of walking it.
+    fn last(mut self) -> Option<Self::Item> {
+        self.next_back()
+    }
+}
+
+/// Walking backwards has no grammar to follow, so every point is computed
+/// with `hilbert_to_xy` in O(depth). Both ends share `remaining`, so they
+/// stop when they meet.
+impl DoubleEndedIterator for HilbertIter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        self.back -= 1;
+        let (i, j) = hilbert_to_xy(self.back, self.depth);
+        Some((self.back, (i + self.offset.0, j + self.offset.1)))
+    }
+}
+
+impl ExactSizeIterator for HilbertIter {}
+
+/// A precomputed Hilbert curve over an `n x n` grid, `n = 2^order`.
+///
+/// Generating the curve is the expensive part of the Hilbert product, so the
+/// coordinates and the row-major permutation are kept around and reused for
+/// every matrix multiplied with this curve.
+pub struct HilbertCurve {
+    order: usize,
+    coordinates: Vec<(usize, Coordinates)>,
+    permutation: Vec<usize>,
+}
+
+impl HilbertCurve {
+    pub fn new(order: usize) -> Self {
+        Self::from_coordinates(order, HilbertIter::new(order).collect())
+    }
+
+    fn from_coordinates(order: usize, coordinates: Vec<(usize, Coordinates)>) -> Self {
+        let n = 2usize.pow(order as u32);
+        let permutation = coordinates
+            .iter()
+            .map(|(_, (i, j))| flat_index(*i, *j, n))
+            .collect();
+        Self {
+            order,
+            coordinates,
+            permutation,
+        }
+    }
+
+    pub fn order(&self) -> usize {
+        self.order
+    }
+
+    /// Side length of the grid.
+    pub fn n(&self) -> usize {
+        2usize.pow(self.order as u32)
+    }
+
+    pub fn coordinates(&self) -> &[(usize, Coordinates)] {
+        &self.coordinates
+    }
+
+    /// Grow the curve to `new_order` without re-running the L-system.
+    ///
+    /// One level up, the curve is `A ↑ H → H ↓ B`: the two middle quadrants are
+    /// translated copies of the current curve, and `A` / `B` are its transpose
+    /// and anti-transpose, so each doubling only remaps existing points.
+    pub fn extend_to(&mut self, new_order: usize) {
+        assert!(
+            new_order >= self.order,
+            "cannot shrink a curve of order {} to order {}",
+            self.order,
+            new_order
+        );
+        while self.order < new_order {
+            let s = self.n();
+            let points = || self.coordinates.iter().map(|(_, c)| *c);
+            let coordinates: Vec<Coordinates> = points()
+                .map(|(i, j)| (j, i))
+                .chain(points().map(|(i, j)| (i + s, j)))
+                .chain(points().map(|(i, j)| (i + s, j + s)))
+                .chain(points().map(|(i, j)| (s - 1 - j, 2 * s - 1 - i)))
+                .collect();
+            *self = Self::from_coordinates(
+                self.order + 1,
+                coordinates.into_iter().enumerate().collect(),
+            );
+        }
+    }
+
+    /// Compute `A * v` for a row-major `A`, flattening it along the curve first.
+    #[allow(non_snake_case)]
+    #[track_caller]
+    pub fn matvec(&self, A_rowmajor: &[i32], v: &[i32]) -> Vec<i32> {
+        let n = self.n();
+        assert_dimensions(n, A_rowmajor);
+        assert_eq!(v.len(), n, "expected a vector of length {}", n);
+
+        let flattened_A: Vec<_> = self.permutation.iter().map(|&k| A_rowmajor[k]).collect();
+        let mut output = vec![0; n];
+        hilbert_matrix_vector_product(&flattened_A, v, &mut output, &self.coordinates);
+        output
+    }
+}
+
+/// Window length used by `CurveMetrics::new` callers that have no better choice.
+pub const METRICS_WINDOW: usize = 16;
+
+/// Locality metrics of an ordering; for all fields, smaller is better.
+///
+/// The derived `PartialOrd` compares the fields in declaration order, so
+/// orderings are ranked by average step distance first and by window span
+/// to break ties (e.g. Hilbert vs. snake, which both only take unit steps).
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub struct CurveMetrics {
+    /// Mean Euclidean distance between consecutive cells.
+    pub average_distance: f64,
+    /// Mean number of distinct rows plus columns touched by a window of
+    /// consecutive cells, i.e. the entries of `output` and `v` it needs.
+    pub average_window_span: f64,
+    /// Largest Euclidean distance between consecutive cells.
+    pub max_distance: f64,
+    /// Mean bounding-box area of a window of consecutive cells.
+    pub average_window_area: f64,
+}
+
+#[cfg(feature = "std")]
+impl CurveMetrics {
+    /// Metrics of `coordinate_iter`, with windows of `window` consecutive
+    /// cells; `window` must be positive.
+    #[track_caller]
+    pub fn new(coordinate_iter: &[(usize, Coordinates)], window: usize) -> Self {
+        assert!(window > 0, "a window holds at least one cell");
+        let distances = step_distances(coordinate_iter);
+        let boxes: Vec<(usize, usize)> = coordinate_iter
+            .chunks(window)
+            .map(|chunk| {
+                let (i_min, i_max, j_min, j_max) = chunk.iter().fold(
+                    (usize::MAX, 0, usize::MAX, 0),
+                    |(i_min, i_max, j_min, j_max), (_, (i, j))| {
+                        (i_min.min(*i), i_max.max(*i), j_min.min(*j), j_max.max(*j))
+                    },
+                );
+                (i_max - i_min + 1, j_max - j_min + 1)
+            })
+            .collect();
+        let spans: Vec<f64> = boxes.iter().map(|(h, w)| (h + w) as f64).collect();
+        let areas: Vec<f64> = boxes.iter().map(|(h, w)| (h * w) as f64).collect();
+        Self {
+            average_distance: mean(&distances),
+            average_window_span: mean(&spans),
+            max_distance: distances.iter().copied().fold(0.0, f64::max),
+            average_window_area: mean(&areas),
+        }
+    }
+}
+
+/// `CurveMetrics` of the Hilbert curve of the given order, with `METRICS_WINDOW`.
+#[cfg(feature = "std")]
+pub fn locality_stats(order: usize) -> CurveMetrics {
+    let coordinate_iter: Vec<_> = HilbertIter::new(order).collect();
+    CurveMetrics::new(&coordinate_iter, METRICS_WINDOW)
+}
+
+/// Average 2D distance between cells at consecutive flattened positions.
+///
+/// Elements next to each other after `flatten_matrix` should be close in the
+/// original matrix: this is 1 for the Hilbert curve and close to 2 for large
+/// row-major matrices, where every row end jumps back across the matrix.
+#[cfg(feature = "std")]
+pub fn flatten_adjacency_distance(coordinate_iter: &[(usize, Coordinates)]) -> f64 {
+    mean(&step_distances(coordinate_iter))
+}
+
+/// Euclidean distances between consecutive cells.
+#[cfg(feature = "std")]
+fn step_distances(coordinate_iter: &[(usize, Coordinates)]) -> Vec<f64> {
+    coordinate_iter
+        .windows(2)
+        .map(|pair| {
+            let (_, (i0, j0)) = pair[0];
+            let (_, (i1, j1)) = pair[1];
+            let di = i0.abs_diff(i1) as f64;
+            let dj = j0.abs_diff(j1) as f64;
+            (di * di + dj * dj).sqrt()
+        })
+        .collect()
+}
+
+#[cfg(feature = "std")]
+fn mean(values: &[f64]) -> f64 {
+    if values.is_empty() {
+        0.0
+    } else {
+        values.iter().sum::<f64>() / values.len() as f64
+    }
+}
+
+/// A fully associative LRU cache model, used to study the working set of a
+/// traversal. Lines are opaque ids; see `access_recency_grid`.
+pub struct ReuseDistanceCache {
+    /// Most recently used line first.
+    lines: VecDeque<usize>,
+    capacity: usize,
+}
+
+impl ReuseDistanceCache {
+    /// A cache holding `capacity` lines, which must be positive.
+    #[track_caller]
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "a cache needs room for at least one line");
+        Self {
+            lines: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Touch `line`. Returns the number of distinct other lines touched since
+    /// its previous access, or `None` if it was never seen or has been evicted.
+    pub fn access(&mut self, line: usize) -> Option<usize> {
+        let distance = self.lines.iter().position(|&l| l == line);
+        match distance {
+            Some(distance) => {
+                self.lines.remove(distance);
+            }
+            None if self.lines.len() == self.capacity => {
+                self.lines.pop_back();
+            }
+            None => {}
+        }
+        self.lines.push_front(line);
+        distance
+    }
+}
+
+/// How "cold" each cell's operands are when the product reaches it, for
+/// rendering as a heatmap.
+///
+/// Cells are visited in `coordinate_iter` order; each touches the cache line
+/// of `output[i]` and of `v[j]` (`cache_line` elements per line). The cell gets
+/// the larger reuse distance of the two, saturated at 255, which also marks a
+/// cold miss. `cache_line` must be positive.
+#[track_caller]
+pub fn access_recency_grid(
+    coordinate_iter: &[(usize, Coordinates)],
+    n: usize,
+    cache_line: usize,
+) -> Vec<Vec<u8>> {
+    assert!(cache_line > 0, "a cache line holds at least one element");
+    let mut cache = ReuseDistanceCache::new(u8::MAX as usize);
+    let mut grid = vec![vec![u8::MAX; n]; n];
+    for (_, (i, j)) in coordinate_iter {
+        // Even ids are lines of `output`, odd ids lines of `v`.
+        let output_line = cache.access(2 * (i / cache_line));
+        let v_line = cache.access(2 * (j / cache_line) + 1);
+        let distance = match (output_line, v_line) {
+            (Some(a), Some(b)) => a.max(b),
+            _ => u8::MAX as usize,
+        };
+        grid[*i][*j] = distance.min(u8::MAX as usize) as u8;
+    }
+    grid
+}
+
+impl TryFrom<Vec<(usize, Coordinates)>> for HilbertCurve {
+    type Error = CurveError;
+
+    /// Adopt a curve generated elsewhere, checking that it is the Hilbert
+    /// curve of the order implied by its length.
+    fn try_from(points: Vec<(usize, Coordinates)>) -> Result<Self, Self::Error> {
+        let order = check_hilbert(&points)?;
+        Ok(Self::from_coordinates(order, points))
+    }
+}
+
+/// `for (t, (i, j)) in &curve`, as often as needed.
+impl<'a> IntoIterator for &'a HilbertCurve {
+    type Item = &'a (usize, Coordinates);
+    type IntoIter = core::slice::Iter<'a, (usize, Coordinates)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.coordinates.iter()
+    }
+}
+
+/// A precomputed curve that can be saved once and loaded at startup instead
+/// of being regenerated.
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct HilbertOrdering(pub Vec<(usize, Coordinates)>);
+
+#[cfg(feature = "serde")]
+impl HilbertOrdering {
+    pub fn new(depth: usize) -> Self {
+        Self(HilbertIter::new(depth).collect())
+    }
+
+    /// Write the ordering to `path` with bincode.
+    pub fn save_to<P: AsRef<std::path::Path>>(&self, path: P) -> eyre::Result<()> {
+        let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        bincode::serialize_into(file, self)?;
+        Ok(())
+    }
+
+    /// Read an ordering written by `save_to`.
+    pub fn load_from<P: AsRef<std::path::Path>>(path: P) -> eyre::Result<Self> {
+        let file = std::io::BufReader::new(std::fs::File::open(path)?);
+        Ok(bincode::deserialize_from(file)?)
+    }
+}
+
+/// Why a sequence of points is not the expected curve.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CurveError {
+    /// The number of points is not `4^order` for any order.
+    InvalidLength(usize),
+    /// The point at `index` differs from the Hilbert curve.
+    Mismatch { index: usize },
+    /// The curve has `len` points instead of the `expected` ones.
+    LengthMismatch { expected: usize, len: usize },
+    /// The point at `index` is numbered `t` instead of `index`.
+    IndexGap { index: usize, t: usize },
+    /// The point at `index` lies outside the grid.
+    OutOfBounds { index: usize, point: Coordinates },
+    /// The point at `index` was already visited.
+    Revisited { index: usize, point: Coordinates },
+    /// The point at `index` is not a unit step away from the one before it.
+    NotAdjacent {
+        index: usize,
+        from: Coordinates,
+        to: Coordinates,
+    },
+}
+
+impl core::fmt::Display for CurveError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            CurveError::InvalidLength(len) => {
+                write!(f, "{} points do not fill a 2^k x 2^k grid", len)
+            }
+            CurveError::Mismatch { index } => {
+                write!(f, "point {} is not on the Hilbert curve", index)
+            }
+            CurveError::LengthMismatch { expected, len } => {
+                write!(f, "expected {} points, but got {}", expected, len)
+            }
+            CurveError::IndexGap { index, t } => {
+                write!(f, "point {} is numbered {}", index, t)
+            }
+            CurveError::OutOfBounds { index, point } => {
+                write!(f, "point {} at {:?} is outside the grid", index, point)
+            }
+            CurveError::Revisited { index, point } => {
+                write!(f, "point {} revisits {:?}", index, point)
+            }
+            CurveError::NotAdjacent { index, from, to } => write!(
+                f,
+                "point {} steps from {:?} to {:?}, which are not neighbours",
+                index, from, to
+            ),
+        }
+    }
+}
+
+impl core::error::Error for CurveError {}
+
+/// Why the Hilbert setup could not produce a curve.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HilbertError {
+    /// The matrix has `len` elements instead of `n * n`.
+    DimensionMismatch { n: usize, len: usize },
+    /// The matrix has `len` elements instead of `rows * cols`.
+    ShapeMismatch {
+        rows: usize,
+        cols: usize,
+        len: usize,
+    },
+}
+
+impl core::fmt::Display for HilbertError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            HilbertError::DimensionMismatch { n, len } => write!(
+                f,
+                "expected an {}x{} matrix with {} elements, but got {} elements",
+                n,
+                n,
+                n * n,
+                len
+            ),
+            HilbertError::ShapeMismatch { rows, cols, len } => write!(
+                f,
+                "expected a {}x{} matrix with {} elements, but got {} elements",
+                rows,
+                cols,
+                rows * cols,
+                len
+            ),
+        }
+    }
+}
+
+impl core::error::Error for HilbertError {}
+
+/// `true` if `points` is exactly the Hilbert curve of some order, as produced
+/// by `HilbertIter`.
+pub fn is_valid_hilbert(points: &[(usize, Coordinates)]) -> bool {
+    check_hilbert(points).is_ok()
+}
+
+/// Infer the order of `points` and compare them against the generated curve.
+fn check_hilbert(points: &[(usize, Coordinates)]) -> Result<usize, CurveError> {
+    let len = points.len();
+    if !is_power_of_two(len) || !len.trailing_zeros().is_multiple_of(2) {
+        return Err(CurveError::InvalidLength(len));
+    }
+    let order = len.trailing_zeros() as usize / 2;
+    match points
+        .iter()
+        .zip(HilbertIter::new(order))
+        .position(|(point, expected)| *point != expected)
+    {
+        Some(index) => Err(CurveError::Mismatch { index }),
+        None => Ok(order),
+    }
+}
+
+/// The Hilbert curve of `depth` as an SVG document with a single `<polyline>`
+/// through the centres of its cells, `cell_size` units apart.
+///
+/// Rows run down and columns to the right, as in the matrix.
+pub fn curve_to_svg(depth: usize, cell_size: f64) -> String {
+    use core::fmt::Write;
+
+    let size = (1usize << depth) as f64 * cell_size;
+    let mut svg = String::new();
+    // Writing to a `String` cannot fail.
+    writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 {size} {size}" width="{size}" height="{size}">"#,
+        size = size
+    )
+    .unwrap();
+    svg.push_str(r#"<polyline fill="none" stroke="black" points=""#);
+    for (t, (i, j)) in HilbertIter::new(depth) {
+        if t > 0 {
+            svg.push(' ');
+        }
+        let x = (j as f64 + 0.5) * cell_size;
+        let y = (i as f64 + 0.5) * cell_size;
+        write!(svg, "{},{}", x, y).unwrap();
+    }
+    svg.push_str("\"/>\n</svg>\n");
+    svg
+}
+
+/// Check that `HilbertIter::new(depth)` is a space-filling curve: it is
+/// numbered `0..n * n` in order, visits every cell of the `n x n` grid exactly
+/// once and only ever moves to a neighbouring cell.
+///
+/// Unlike `is_valid_hilbert` this doesn't compare against the generator, so it
+/// catches regressions in the grammar itself.
+pub fn validate_curve(depth: usize) -> Result<(), CurveError> {
+    let n = 1 << depth;
+    let points: Vec<_> = HilbertIter::new(depth).collect();
+    if points.len() != n * n {
+        return Err(CurveError::LengthMismatch {
+            expected: n * n,
+            len: points.len(),
+        });
+    }
+    let mut visited = vec![false; n * n];
+    let mut previous: Option<Coordinates> = None;
+    for (index, &(t, point)) in points.iter().enumerate() {
+        let (i, j) = point;
+        if t != index {
+            return Err(CurveError::IndexGap { index, t });
+        }
+        if i >= n || j >= n {
+            return Err(CurveError::OutOfBounds { index, point });
+        }
+        if core::mem::replace(&mut visited[flat_index(i, j, n)], true) {
+            return Err(CurveError::Revisited { index, point });
+        }
+        if let Some(from) = previous {
+            if from.0.abs_diff(i) + from.1.abs_diff(j) != 1 {
+                return Err(CurveError::NotAdjacent {
+                    index,
+                    from,
+                    to: point,
+                });
+            }
+        }
+        previous = Some(point);
+    }
+    Ok(())
+}
+
+/// How many `↑`, `↓`, `←`, `→` moves (in that order) the curve of `order` makes.
+///
+/// A cheap grammar check: the curve runs from `(0, 0)` to `(0, n - 1)`, so
+/// `↑` and `↓` cancel and `→` exceeds `←` by `n - 1`. The counts cannot all
+/// be equal, as there are `n² - 1` moves in total; for `order >= 1` there are
+/// `n² / 4` moves each way vertically.
+pub fn direction_histogram(order: usize) -> [usize; 4] {
+    use core::cmp::Ordering;
+
+    let mut histogram = [0; 4];
+    let mut previous = (0, 0);
+    for (i, j) in HilbertIter::new(order).coords().skip(1) {
+        let direction = match (i.cmp(&previous.0), j.cmp(&previous.1)) {
+            (Ordering::Greater, _) => 0,
+            (Ordering::Less, _) => 1,
+            (_, Ordering::Less) => 2,
+            _ => 3,
+        };
+        histogram[direction] += 1;
+        previous = (i, j);
+    }
+    histogram
+}
+
+/// Write the Hilbert curve of `order` to a Parquet file with the `UInt64`
+/// columns `index`, `i` and `j`.
+#[cfg(feature = "arrow")]
+pub fn write_curve_parquet<P: AsRef<std::path::Path>>(order: usize, path: P) -> eyre::Result<()> {
+    use arrow_array::{ArrayRef, RecordBatch, UInt64Array};
+    use std::sync::Arc;
+
+    let curve: Vec<_> = HilbertIter::new(order).collect();
+    let column = |f: fn(&(usize, Coordinates)) -> usize| -> ArrayRef {
+        Arc::new(UInt64Array::from_iter_values(
+            curve.iter().map(|point| f(point) as u64),
+        ))
+    };
+    let batch = RecordBatch::try_from_iter([
+        ("index", column(|(t, _)| *t)),
+        ("i", column(|(_, (i, _))| *i)),
+        ("j", column(|(_, (_, j))| *j)),
+    ])?;
+
+    let file = std::fs::File::create(path)?;
+    let mut writer = parquet::arrow::ArrowWriter::try_new(file, batch.schema(), None)?;
+    writer.write(&batch)?;
+    writer.close()?;
+    Ok(())
+}
+
+/// A contiguous chunk of the curve and the cells it covers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TilePlan {
+    /// Curve indices of the tile.
+    pub range: core::ops::Range<usize>,
+    /// Smallest `(i, j)` touched by the tile.
+    pub min: Coordinates,
+    /// Largest `(i, j)` touched by the tile.
+    pub max: Coordinates,
+}
+
+/// Split the Hilbert curve of `order` into consecutive tiles of `tile_elems`
+/// cells (the last one may be shorter), each with its bounding box.
+///
+/// A scheduler can size tiles to the cache and knows each tile's working set
+/// in `output` (rows `min.0..=max.0`) and `v` (columns `min.1..=max.1`).
+pub fn tile_plan(order: usize, tile_elems: usize) -> Vec<TilePlan> {
+    assert!(tile_elems > 0, "tiles must not be empty");
+    let coordinate_iter: Vec<_> = HilbertIter::new(order).collect();
+    coordinate_iter
+        .chunks(tile_elems)
+        .map(|chunk| {
+            let (start, _) = chunk[0];
+            let (min, max) = chunk.iter().fold(
+                ((usize::MAX, usize::MAX), (0, 0)),
+                |(min, max), (_, (i, j))| {
+                    (
+                        (min.0.min(*i), min.1.min(*j)),
+                        (max.0.max(*i), max.1.max(*j)),
+                    )
+                },
+            );
+            TilePlan {
+                range: start..start + chunk.len(),
+                min,
+                max,
+            }
+        })
+        .collect()
+}
+
+/// Boustrophedon ("snake") ordering: even rows left-to-right, odd rows right-to-left.
+///
+/// A cheap baseline for the Hilbert curve: consecutive cells are always
+/// neighbours, but there is no locality across rows.
+pub fn snake_iter(n: usize) -> impl Iterator<Item = (usize, Coordinates)> {
+    (0..n * n).map(move |t| {
+        let i = t / n;
+        let j = if i.is_multiple_of(2) {
+            t % n
+        } else {
+            n - 1 - t % n
+        };
+        (t, (i, j))
+    })
+}
+
+/// The cells of an `n x n` matrix in row-major `block x block` tiles, the
+/// order `blocked_matrix_vector_product` visits them in.
+#[track_caller]
+pub fn blocked_iter(n: usize, block: usize) -> impl Iterator<Item = (usize, Coordinates)> {
+    assert!(block > 0, "block must be positive");
+    (0..n)
+        .step_by(block)
+        .flat_map(move |i0| (0..n).step_by(block).map(move |j0| (i0, j0)))
+        .flat_map(move |(i0, j0)| {
+            (i0..(i0 + block).min(n))
+                .flat_map(move |i| (j0..(j0 + block).min(n)).map(move |j| (i, j)))
+        })
+        .enumerate()
+}
+
+/// Z-order (Morton) curve of order `depth`: `t` is the bit interleaving of
+/// `(i, j)`, with the row bits in the odd positions.
+///
+/// Simpler than the Hilbert curve, but it jumps between quadrants.
+pub fn morton_iter(depth: usize) -> impl Iterator<Item = (usize, Coordinates)> {
+    (0..1usize << (2 * depth)).map(|t| {
+        let (mut i, mut j) = (0, 0);
+        for bit in 0..usize::BITS as usize / 2 {
+            i |= ((t >> (2 * bit + 1)) & 1) << bit;
+            j |= ((t >> (2 * bit)) & 1) << bit;
+        }
+        (t, (i, j))
+    })
+}
+
+/// Morton code of a point in `coords.len()` dimensions with `bits` bits per
+/// coordinate: bit `b` of `coords[k]` lands at `b * d + (d - 1 - k)`, so the
+/// first coordinate takes the most significant bit of each group. In 2D,
+/// `morton_encode(&[i, j], depth)` is the `t` of `(i, j)` in `morton_iter`.
+#[track_caller]
+pub fn morton_encode(coords: &[usize], bits: usize) -> u128 {
+    let d = coords.len();
+    assert!(
+        d * bits <= u128::BITS as usize,
+        "{} dimensions of {} bits don't fit u128",
+        d,
+        bits
+    );
+    assert!(
+        bits <= usize::BITS as usize,
+        "{} bits don't fit usize",
+        bits
+    );
+    let mut code = 0;
+    for (k, &x) in coords.iter().enumerate() {
+        assert!(
+            bits == usize::BITS as usize || x >> bits == 0,
+            "coordinate {} does not fit {} bits",
+            x,
+            bits
+        );
+        for b in 0..bits {
+            code |= (((x >> b) & 1) as u128) << (b * d + (d - 1 - k));
+        }
+    }
+    code
+}
+
+/// The inverse of `morton_encode`: the `dims` coordinates of `code`.
+#[track_caller]
+pub fn morton_decode(code: u128, dims: usize, bits: usize) -> Vec<usize> {
+    assert!(
+        dims * bits <= u128::BITS as usize,
+        "{} dimensions of {} bits don't fit u128",
+        dims,
+        bits
+    );
+    assert!(
+        bits <= usize::BITS as usize,
+        "{} bits don't fit usize",
+        bits
+    );
+    (0..dims)
+        .map(|k| {
+            (0..bits).fold(0, |x, b| {
+                x | ((((code >> (b * dims + (dims - 1 - k))) & 1) as usize) << b)
+            })
+        })
+        .collect()
+}
+
+/// Symbols of the Peano L-system: `X -> XFYFX+F+YFXFY-F-XFYFX`,
+/// `Y -> YFXFY-F-XFYFX+F+YFXFY`, where `F` is a step and `+`/`-` are quarter
+/// turns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PeanoSymbol {
+    X,
+    Y,
+    Forward,
+    Plus,
+    Minus,
+}
+
+const PEANO_X: [PeanoSymbol; 21] = peano_production("XFYFX+F+YFXFY-F-XFYFX");
+const PEANO_Y: [PeanoSymbol; 21] = peano_production("YFXFY-F-XFYFX+F+YFXFY");
+
+const fn peano_production(rule: &str) -> [PeanoSymbol; 21] {
+    let rule = rule.as_bytes();
+    let mut production = [PeanoSymbol::Forward; 21];
+    let mut k = 0;
+    while k < production.len() {
+        production[k] = match rule[k] {
+            b'X' => PeanoSymbol::X,
+            b'Y' => PeanoSymbol::Y,
+            b'F' => PeanoSymbol::Forward,
+            b'+' => PeanoSymbol::Plus,
+            b'-' => PeanoSymbol::Minus,
+            _ => panic!("not a Peano symbol"),
+        };
+        k += 1;
+    }
+    production
+}
+
+/// Walks the Peano curve by expanding the L-system depth first, so only one
+/// production per level is kept.
+struct PeanoIter {
+    /// The unexpanded rest of each production being walked, and its depth.
+    stack: Vec<(&'static [PeanoSymbol], usize)>,
+    index: usize,
+    remaining: usize,
+    i: usize,
+    j: usize,
+    /// Heading as `(di, dj)`.
+    direction: (isize, isize),
+}
+
+impl Iterator for PeanoIter {
+    type Item = (usize, Coordinates);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        if self.index == 0 {
+            // As with `HilbertIter`, the grammar only emits moves.
+            self.index = 1;
+            self.remaining -= 1;
+            return Some((0, (0, 0)));
+        }
+        while let Some((symbols, depth)) = self.stack.last_mut() {
+            let Some((&symbol, rest)) = symbols.split_first() else {
+                self.stack.pop();
+                continue;
+            };
+            *symbols = rest;
+            let depth = *depth;
+            let (di, dj) = self.direction;
+            match symbol {
+                PeanoSymbol::X if depth > 0 => self.stack.push((&PEANO_X, depth - 1)),
+                PeanoSymbol::Y if depth > 0 => self.stack.push((&PEANO_Y, depth - 1)),
+                // Non-terminals at depth 0 expand to nothing.
+                PeanoSymbol::X | PeanoSymbol::Y => {}
+                PeanoSymbol::Plus => self.direction = (-dj, di),
+                PeanoSymbol::Minus => self.direction = (dj, -di),
+                PeanoSymbol::Forward => {
+                    self.i = self.i.wrapping_add_signed(di);
+                    self.j = self.j.wrapping_add_signed(dj);
+                    let t = self.index;
+                    self.index += 1;
+                    self.remaining -= 1;
+                    return Some((t, (self.i, self.j)));
+                }
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+/// Peano curve of order `depth` over a `3^depth x 3^depth` grid, from
+/// `(0, 0)` to the opposite corner.
+///
+/// Like the Hilbert curve every step goes to a neighbouring cell, but each
+/// level splits the grid into 3 x 3 blocks instead of 2 x 2.
+pub fn peano_iter(depth: usize) -> impl Iterator<Item = (usize, Coordinates)> {
+    PeanoIter {
+        stack: vec![(&[PeanoSymbol::X], depth)],
+        index: 0,
+        remaining: 9usize.pow(depth as u32),
+        i: 0,
+        j: 0,
+        direction: (1, 0),
+    }
+}
+
+/// Read a dense square matrix from CSV, one row per line.
+///
+/// Returns the row-major data and `n`. Blank lines are ignored.
+#[cfg(feature = "cli")]
+pub fn load_matrix_csv<R: Read>(reader: R) -> eyre::Result<(Vec<i32>, usize)> {
+    let mut data = Vec::new();
+    let mut n = None;
+    let mut rows = 0;
+    for (line_number, line) in BufReader::new(reader).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let before = data.len();
+        for value in line.split(',') {
+            let value = value.trim();
+            data.push(value.parse().wrap_err_with(|| {
+                format!("line {}: invalid entry {:?}", line_number + 1, value)
+            })?);
+        }
+        let columns = data.len() - before;
+        match n {
+            None => n = Some(columns),
+            Some(n) if n != columns => eyre::bail!(
+                "line {}: expected {} columns, found {}",
+                line_number + 1,
+                n,
+                columns
+            ),
+            Some(_) => {}
+        }
+        rows += 1;
+    }
+    let n = n.unwrap_or(0);
+    if rows != n {
+        eyre::bail!("matrix is not square: {} rows, {} columns", rows, n);
+    }
+    Ok((data, n))
+}
+
+/// First index at which `a` and `b` differ, with both values.
+///
+/// Only the common prefix is compared; check the lengths separately.
+pub fn first_divergence(a: &[i32], b: &[i32]) -> Option<(usize, i32, i32)> {
+    a.iter()
+        .zip(b)
+        .enumerate()
+        .find(|(_, (x, y))| x != y)
+        .map(|(index, (x, y))| (index, *x, *y))
+}
+
+/// Summary of a product's output, readable where the vector itself isn't.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OutputStats {
+    pub min: i32,
+    pub max: i32,
+    pub mean: f64,
+    pub sum: i64,
+}
+
+/// `OutputStats` of a non-empty `output`, in a single pass.
+pub fn product_stats(output: &[i32]) -> OutputStats {
+    assert!(!output.is_empty(), "cannot summarize an empty output");
+    let (min, max, sum) = output
+        .iter()
+        .fold((i32::MAX, i32::MIN, 0i64), |(min, max, sum), x| {
+            (min.min(*x), max.max(*x), sum + *x as i64)
+        });
+    OutputStats {
+        min,
+        max,
+        mean: sum as f64 / output.len() as f64,
+        sum,
+    }
+}
+
+/// Seed used by the binaries and benchmarks unless told otherwise.
+pub const DEFAULT_SEED: u64 = 10;
+
+/// The RNG behind all generated inputs, so runs with the same seed are identical.
+#[cfg(feature = "cli")]
+pub fn seeded_rng(seed: u64) -> ChaCha8Rng {
+    ChaCha8Rng::seed_from_u64(seed)
+}
+
+/// A rayon thread pool with `threads` workers, or one per core for `None`.
+#[cfg(all(feature = "cli", feature = "rayon"))]
+pub fn thread_pool(threads: Option<usize>) -> eyre::Result<rayon::ThreadPool> {
+    let mut builder = rayon::ThreadPoolBuilder::new();
+    if let Some(threads) = threads {
+        builder = builder.num_threads(threads);
+    }
+    Ok(builder.build()?)
+}
+
+/// Pin the current thread to the `index`-th core reported by the OS, so the
+/// scheduler can't migrate a benchmark between cores (e.g. P- and E-cores).
+///
+/// Returns `false` if the core doesn't exist or the platform doesn't support it.
+#[cfg(feature = "core_affinity")]
+pub fn pin_to_core(index: usize) -> bool {
+    core_affinity::get_core_ids()
+        .and_then(|ids| ids.get(index).copied())
+        .map(core_affinity::set_for_current)
+        .unwrap_or(false)
+}
+
+/// Handle the binaries' `--core N` flag: pin to core `N` (default 0) when
+/// built with the `core_affinity` feature, and reject the flag otherwise.
+/// Returns whether the current thread ended up pinned.
+#[cfg(feature = "cli")]
+pub fn pin_core(core: Option<usize>) -> eyre::Result<bool> {
+    #[cfg(feature = "core_affinity")]
+    return Ok(pin_to_core(core.unwrap_or(0)));
+    #[cfg(not(feature = "core_affinity"))]
+    {
+        if core.is_some() {
+            eyre::bail!("--core requires the `core_affinity` feature");
+        }
+        Ok(false)
+    }
+}
+
+/// Whether an entry of `A * v` can exceed `i32::MAX` for an `n x n` matrix
+/// whose entries (and those of `v`) are at most `max_entry` in magnitude.
+pub fn product_may_overflow(n: usize, max_entry: i32) -> bool {
+    let max_entry = max_entry.unsigned_abs() as u128;
+    n as u128 * max_entry * max_entry > i32::MAX as u128
+}
+
+/// Largest entry `setup_inputs` draws; `product_may_overflow(n, INPUT_MAX)`
+/// tells whether its products can overflow.
+pub const INPUT_MAX: i32 = 10;
+
+/// Generate (A, v) as inputs for matrix multiplication, with entries in
+/// `1..=INPUT_MAX`.
+#[cfg(feature = "cli")]
+pub fn setup_inputs(n: usize, rng: &mut ChaCha8Rng) -> (Vec<i32>, Vec<i32>) {
+    let (low, high) = (1, INPUT_MAX + 1);
+    let range = Uniform::new(low, high);
+    #[allow(non_snake_case)]
+    let A = make_matrix(n, low, high, rng);
+    let v: Vec<_> = (0..n).map(|_| rng.sample(range)).collect();
+    assert_eq!(v.len(), n);
+    (A, v)
+}
+
+/// Panic (at the caller's location) unless `A` holds exactly `n * n` elements.
+#[allow(non_snake_case)]
+#[track_caller]
+fn assert_dimensions(n: usize, A: &[i32]) {
+    assert_eq!(
+        n * n,
+        A.len(),
+        "expected an {}x{} matrix with {} elements, but got {} elements",
+        n,
+        n,
+        n * n,
+        A.len()
+    );
+}
+
+/// Setup (coordinates, flattened_A) for Hilbert multiplication
+///
+/// `n` need not be a power of two: the curve is generated for `padded_order(n)`
+/// and the cells outside the matrix are dropped, renumbering `t` to stay dense.
+#[allow(non_snake_case)]
+#[track_caller]
+pub fn setup_hilbert(n: usize, A: Vec<i32>) -> (Vec<(usize, Coordinates)>, Vec<i32>) {
+    match try_setup_hilbert(n, A) {
+        Ok(setup) => setup,
+        Err(e) => panic!("{}", e),
+    }
+}
+
+/// `setup_hilbert`, returning an error instead of panicking.
+#[allow(non_snake_case, clippy::type_complexity)]
+pub fn try_setup_hilbert(
+    n: usize,
+    A: Vec<i32>,
+) -> Result<(Vec<(usize, Coordinates)>, Vec<i32>), HilbertError> {
+    if A.len() != n * n {
+        return Err(HilbertError::DimensionMismatch { n, len: A.len() });
+    }
+    try_setup_hilbert_rect(n, n, A)
+}
+
+/// Setup (coordinates, flattened_A) for a row-major `rows x cols` matrix.
+///
+/// The curve covers `max(rows, cols)` padded to a power of two; the cells
+/// outside the matrix are dropped. Use with `v` of length `cols` and `output`
+/// of length `rows`.
+#[allow(non_snake_case)]
+#[track_caller]
+pub fn setup_hilbert_rect(
+    rows: usize,
+    cols: usize,
+    A: Vec<i32>,
+) -> (Vec<(usize, Coordinates)>, Vec<i32>) {
+    match try_setup_hilbert_rect(rows, cols, A) {
+        Ok(setup) => setup,
+        Err(e) => panic!("{}", e),
+    }
+}
+
+/// `setup_hilbert_rect`, returning an error instead of panicking.
+#[allow(non_snake_case, clippy::type_complexity)]
+pub fn try_setup_hilbert_rect(
+    rows: usize,
+    cols: usize,
+    A: Vec<i32>,
+) -> Result<(Vec<(usize, Coordinates)>, Vec<i32>), HilbertError> {
+    if A.len() != rows * cols {
+        return Err(HilbertError::ShapeMismatch {
+            rows,
+            cols,
+            len: A.len(),
+        });
+    }
+    #[cfg(feature = "std")]
+    let curve = cached_coordinates(padded_order(rows.max(cols)));
+    #[cfg(not(feature = "std"))]
+    let curve: Vec<_> = HilbertIter::new(padded_order(rows.max(cols))).collect();
+    let hilbert_iter: Vec<_> = curve
+        .iter()
+        .filter(|(_, (i, j))| *i < rows && *j < cols)
+        .enumerate()
+        .map(|(t, (_, ij))| (t, *ij))
+        .collect();
+
+    #[allow(non_snake_case)]
+    let mut flattened_A = vec![0; rows * cols];
+    for (t, (i, j)) in &hilbert_iter {
+        flattened_A[*t] = A[flat_index(*i, *j, cols)];
+    }
+    Ok((hilbert_iter, flattened_A))
+}
+
+/// The collected `HilbertIter::new(depth)`, generated once per depth and then
+/// shared. Cached curves are kept for the lifetime of the process.
+#[cfg(feature = "std")]
+pub fn cached_coordinates(depth: usize) -> Arc<Vec<(usize, Coordinates)>> {
+    type SharedCurve = Arc<Vec<(usize, Coordinates)>>;
+    static CACHE: OnceLock<Mutex<HashMap<usize, SharedCurve>>> = OnceLock::new();
+    let mut cache = CACHE
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    Arc::clone(
+        cache
+            .entry(depth)
+            .or_insert_with(|| Arc::new(HilbertIter::new(depth).collect())),
+    )
+}
+
+/// Setup (coordinates, flattened_A) for the snake ordering.
+///
+/// The result can be fed to `hilbert_matrix_vector_product` unchanged.
+#[allow(non_snake_case)]
+#[track_caller]
+pub fn setup_snake(n: usize, A: Vec<i32>) -> (Vec<(usize, Coordinates)>, Vec<i32>) {
+    assert_dimensions(n, &A);
+    let snake: Vec<_> = snake_iter(n).collect();
+
+    #[allow(non_snake_case)]
+    let mut flattened_A = vec![0; n * n];
+    for (t, (i, j)) in &snake {
+        flattened_A[*t] = A[flat_index(*i, *j, n)];
+    }
+    (snake, flattened_A)
+}
+
+/// Setup (coordinates, flattened_A) for the Peano ordering.
+///
+/// `n` is padded to a power of three and the cells outside the matrix are
+/// dropped, like `setup_hilbert` does with powers of two. The result can be
+/// fed to `hilbert_matrix_vector_product` unchanged.
+#[allow(non_snake_case)]
+#[track_caller]
+pub fn setup_peano(n: usize, A: Vec<i32>) -> (Vec<(usize, Coordinates)>, Vec<i32>) {
+    assert_dimensions(n, &A);
+    let mut depth = 0;
+    while 3usize.pow(depth as u32) < n {
+        depth += 1;
+    }
+    let peano: Vec<_> = peano_iter(depth)
+        .filter(|(_, (i, j))| *i < n && *j < n)
+        .enumerate()
+        .map(|(t, (_, ij))| (t, ij))
+        .collect();
+
+    #[allow(non_snake_case)]
+    let mut flattened_A = vec![0; n * n];
+    flatten_matrix_into(&peano, &A, &mut flattened_A, n);
+    (peano, flattened_A)
+}
+
+/// Setup (coordinates, flattened_A) for the Morton ordering.
+///
+/// Like `setup_hilbert`, `n` is padded to a power of two and the cells outside
+/// the matrix are dropped.
+#[allow(non_snake_case)]
+#[track_caller]
+pub fn setup_morton(n: usize, A: Vec<i32>) -> (Vec<(usize, Coordinates)>, Vec<i32>) {
+    assert_dimensions(n, &A);
+    let morton: Vec<_> = morton_iter(padded_order(n))
+        .filter(|(_, (i, j))| *i < n && *j < n)
+        .enumerate()
+        .map(|(t, (_, ij))| (t, ij))
+        .collect();
+
+    #[allow(non_snake_case)]
+    let mut flattened_A = vec![0; n * n];
+    flatten_matrix_into(&morton, &A, &mut flattened_A, n);
+    (morton, flattened_A)
+}
+
+/// Setup (depth, flattened_A) for Hilbert multiplication
+///
+/// The iterator product walks the whole curve, so `n` must be a power of two;
+/// use `setup_hilbert` otherwise.
+#[allow(non_snake_case)]
+#[track_caller]
+pub fn setup_hilbert_iter(n: usize, A: Vec<i32>) -> (usize, Vec<i32>) {
+    assert_dimensions(n, &A);
+    assert!(is_power_of_two(n), "n must be a power of two, got {}", n);
+    let depth = order_of(n);
+
+    #[allow(non_snake_case)]
+    let flattened_A = flatten_matrix(depth, A, n);
+    (depth, flattened_A)
+}
+
+/// Hardware counters per loop, as measured by `count_perf`.
+#[cfg(perf_counters)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct PerformanceCounters {
+    pub cycles: f64,
+    pub branches: f64,
+    pub missed_branches: f64,
+    pub instructions: f64,
+}
+
+#[cfg(perf_counters)]
+impl PerformanceCounters {
+    fn per_loop(
+        cycles: f64,
+        branches: f64,
+        missed_branches: f64,
+        instructions: f64,
+        loops: usize,
+    ) -> Self {
+        let loops = loops as f64;
+        PerformanceCounters {
+            cycles: cycles / loops,
+            branches: branches / loops,
+            missed_branches: missed_branches / loops,
+            instructions: instructions / loops,
+        }
+    }
+}
+
+/// Run `f` `loops` times and return the counters per loop.
+///
+/// The backend depends on the target: `macos-perf` on macOS (needs root),
+/// `perf_event_open` via `linux-perf` on Linux (needs a permissive
+/// `perf_event_paranoid`).
+#[cfg(perf_counters)]
+#[track_caller]
+pub fn count_perf<F: FnMut()>(loops: usize, mut f: F) -> eyre::Result<PerformanceCounters> {
+    assert!(loops > 0, "loops must be positive");
+    #[cfg(target_os = "macos")]
+    {
+        // The counters are configured per thread.
+        if let Err(macos_perf::PerfError::NotInitialized) = macos_perf::get_counters() {
+            macos_perf::init()?;
+        }
+        let start = macos_perf::get_counters()?;
+        for _ in 0..loops {
+            f();
+        }
+        let counters = macos_perf::get_counters()? - start;
+        Ok(PerformanceCounters::per_loop(
+            counters.cycles,
+            counters.branches,
+            counters.missed_branches,
+            counters.instructions,
+            loops,
+        ))
+    }
+    #[cfg(target_os = "linux")]
+    {
+        use perf_event::events::Hardware;
+        use perf_event::{Builder, Group};
+
+        let mut group = Group::new().wrap_err("perf_event_open failed")?;
+        let mut counter = |event: Hardware| {
+            Builder::new()
+                .group(&mut group)
+                .kind(event)
+                .build()
+                .wrap_err_with(|| format!("cannot count {:?}", event))
+        };
+        let cycles = counter(Hardware::CPU_CYCLES)?;
+        let branches = counter(Hardware::BRANCH_INSTRUCTIONS)?;
+        let missed_branches = counter(Hardware::BRANCH_MISSES)?;
+        let instructions = counter(Hardware::INSTRUCTIONS)?;
+
+        group.enable()?;
+        for _ in 0..loops {
+            f();
+        }
+        group.disable()?;
+        let counts = group.read()?;
+        Ok(PerformanceCounters::per_loop(
+            counts[&cycles] as f64,
+            counts[&branches] as f64,
+            counts[&missed_branches] as f64,
+            counts[&instructions] as f64,
+            loops,
+        ))
+    }
+}
+
+/// Relative difference of `b` to `a` per counter, in percent.
+#[cfg(perf_counters)]
+pub fn compare_perf_counters(a: &PerformanceCounters, b: &PerformanceCounters) -> String {
+    let diff = |a: f64, b: f64| 100. * (a - b) / a;
+    format!(
+        "cycles: {:.2}% branches: {:.2}% missed_branches: {:.2}% instructions: {:.2}%",
+        diff(a.cycles, b.cycles),
+        diff(a.branches, b.branches),
+        diff(a.missed_branches, b.missed_branches),
+        diff(a.instructions, b.instructions)
+    )
+}
+
+/// An order in which to visit the cells of a matrix: the scans behind the
+/// naive loops, or one of the curves.
+///
+/// The experiment times the scans with their own loops over the row-major
+/// matrix and the curves with `hilbert_matrix_vector_product`; `setup_curve`
+/// turns any kind into a reordered layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CurveKind {
+    /// Row by row: `naive_matrix_vector_product`.
+    RowMajor,
+    /// Column by column: `naive_matrix_vector_product_colmajor`, the
+    /// worst-case baseline.
+    ColMajor,
+    /// Row-major tiles: `blocked_matrix_vector_product`.
+    Blocked,
+    /// `setup_hilbert`.
+    Hilbert,
+    /// `setup_snake`.
+    Snake,
+    /// `setup_morton`.
+    Morton,
+    /// `setup_peano`.
+    Peano,
+}
+
+impl CurveKind {
+    /// Every kind, in the order the experiment reports them.
+    pub const fn variants() -> [CurveKind; 7] {
+        [
+            CurveKind::RowMajor,
+            CurveKind::ColMajor,
+            CurveKind::Blocked,
+            CurveKind::Hilbert,
+            CurveKind::Snake,
+            CurveKind::Morton,
+            CurveKind::Peano,
+        ]
+    }
+
+    /// The label of this kind's rows in the experiment output.
+    pub fn label(self) -> &'static str {
+        match self {
+            CurveKind::RowMajor => "naive",
+            CurveKind::ColMajor => "colmajor",
+            CurveKind::Blocked => "blocked",
+            CurveKind::Hilbert => "hilbert",
+            CurveKind::Snake => "snake",
+            CurveKind::Morton => "morton",
+            CurveKind::Peano => "peano",
+        }
+    }
+}
+
+/// Setup (coordinates, flattened_A) for any `kind`; `CurveKind::Blocked`
+/// uses `DEFAULT_BLOCK x DEFAULT_BLOCK` tiles.
+///
+/// The result can be fed to `hilbert_matrix_vector_product` unchanged.
+#[allow(non_snake_case)]
+#[track_caller]
+pub fn setup_curve(
+    kind: CurveKind,
+    n: usize,
+    A: Vec<i32>,
+) -> (Vec<(usize, Coordinates)>, Vec<i32>) {
+    let scan: fn(usize, usize) -> Coordinates = match kind {
+        CurveKind::Hilbert => return setup_hilbert(n, A),
+        CurveKind::Snake => return setup_snake(n, A),
+        CurveKind::Morton => return setup_morton(n, A),
+        CurveKind::Peano => return setup_peano(n, A),
+        CurveKind::Blocked => {
+            assert_dimensions(n, &A);
+            let blocked: Vec<_> = blocked_iter(n, DEFAULT_BLOCK).collect();
+            #[allow(non_snake_case)]
+            let mut flattened_A = vec![0; n * n];
+            flatten_matrix_into(&blocked, &A, &mut flattened_A, n);
+            return (blocked, flattened_A);
+        }
+        CurveKind::RowMajor => |t, n| (t / n, t % n),
+        CurveKind::ColMajor => |t, n| (t % n, t / n),
+    };
+    assert_dimensions(n, &A);
+    let coordinates: Vec<_> = (0..n * n).map(|t| (t, scan(t, n))).collect();
+    #[allow(non_snake_case)]
+    let mut flattened_A = vec![0; n * n];
+    flatten_matrix_into(&coordinates, &A, &mut flattened_A, n);
+    (coordinates, flattened_A)
+}
+
+/// Tile side of the blocked baseline: 64 x 64 `i32`s are 16 KiB, half of a
+/// typical L1 data cache.
+pub const DEFAULT_BLOCK: usize = 64;
+
+/// What `run_experiment` measures. Build one with `ExperimentConfig::builder()`
+/// or start from `ExperimentConfig::default()`.
+#[cfg(feature = "cli")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExperimentConfig {
+    /// Matrix sides to time, in order.
+    pub sizes: Vec<usize>,
+    /// Passed to `timeit_loops!` for every measurement.
+    pub repetitions: usize,
+    /// Seed for the inputs; every kind sees the same inputs for a given seed.
+    pub seed: u64,
+    pub curve: CurveKind,
+    /// Tile side for `CurveKind::Blocked`.
+    pub block: usize,
+}
+
+#[cfg(feature = "cli")]
+impl Default for ExperimentConfig {
+    /// The sizes `2^5..2^14`, 20 repetitions, `DEFAULT_SEED`, the Hilbert
+    /// curve and `DEFAULT_BLOCK`.
+    fn default() -> Self {
+        ExperimentConfig {
+            sizes: (5..14).map(|n| 2usize.pow(n)).collect(),
+            repetitions: 20,
+            seed: DEFAULT_SEED,
+            curve: CurveKind::Hilbert,
+            block: DEFAULT_BLOCK,
+        }
+    }
+}
+
+#[cfg(feature = "cli")]
+impl ExperimentConfig {
+    pub fn builder() -> ExperimentConfigBuilder {
+        ExperimentConfigBuilder {
+            config: ExperimentConfig::default(),
+        }
+    }
+}
+
+/// Builder for `ExperimentConfig`; unset fields keep their defaults.
+#[cfg(feature = "cli")]
+#[derive(Debug, Clone)]
+pub struct ExperimentConfigBuilder {
+    config: ExperimentConfig,
+}
+
+#[cfg(feature = "cli")]
+impl ExperimentConfigBuilder {
+    pub fn sizes(mut self, sizes: impl IntoIterator<Item = usize>) -> Self {
+        self.config.sizes = sizes.into_iter().collect();
+        self
+    }
+
+    #[track_caller]
+    pub fn repetitions(mut self, repetitions: usize) -> Self {
+        assert!(repetitions > 0, "repetitions must be positive");
+        self.config.repetitions = repetitions;
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.config.seed = seed;
+        self
+    }
+
+    pub fn curve(mut self, curve: CurveKind) -> Self {
+        self.config.curve = curve;
+        self
+    }
+
+    #[track_caller]
+    pub fn block(mut self, block: usize) -> Self {
+        assert!(block > 0, "block must be positive");
+        self.config.block = block;
+        self
+    }
+
+    pub fn build(self) -> ExperimentConfig {
+        self.config
+    }
+}
+
+/// One measurement of `run_experiment`.
+#[cfg(feature = "cli")]
+#[derive(Debug, Clone)]
+pub struct ExperimentRow {
+    pub curve: CurveKind,
+    /// What was timed: `curve.label()` for the serial products, or one of
+    /// the parallel measurements of `run_scaling`.
+    pub label: &'static str,
+    /// Threads the timed code ran on; 1 for the serial products.
+    pub threads: usize,
+    pub n: usize,
+    /// As returned by `timeit_loops!`.
+    pub seconds: f64,
+    /// `None` if the performance counters could not be read, e.g. when
+    /// `perf_event_open` is denied.
+    #[cfg(perf_counters)]
+    pub counters: Option<PerformanceCounters>,
+}
+
+/// Time `cfg.curve` for every size in `cfg.sizes`, one row per size.
+///
+/// If the performance counters (`count_perf`) cannot be read, the rows are
+/// still timed and their `counters` are `None`.
+#[cfg(feature = "cli")]
+pub fn run_experiment(cfg: &ExperimentConfig) -> Vec<ExperimentRow> {
+    let mut rng = seeded_rng(cfg.seed);
+    let count = cfg.repetitions;
+    let mut rows = Vec::with_capacity(cfg.sizes.len());
+    for &n in &cfg.sizes {
+        #[allow(non_snake_case)]
+        let (A, v) = setup_inputs(n, &mut rng);
+        let mut output = vec![0; n];
+        let row = match cfg.curve {
+            CurveKind::RowMajor => time_product(cfg.curve, cfg.curve.label(), 1, n, count, || {
+                naive_matrix_vector_product(&A, &v, &mut output, n)
+            }),
+            CurveKind::ColMajor => time_product(cfg.curve, cfg.curve.label(), 1, n, count, || {
+                naive_matrix_vector_product_colmajor(&A, &v, &mut output, n)
+            }),
+            CurveKind::Blocked => time_product(cfg.curve, cfg.curve.label(), 1, n, count, || {
+                blocked_matrix_vector_product(&A, &v, &mut output, n, cfg.block)
+            }),
+            CurveKind::Hilbert | CurveKind::Snake | CurveKind::Morton | CurveKind::Peano => {
+                #[allow(non_snake_case)]
+                let (coordinates, flattened_A) = setup_curve(cfg.curve, n, A);
+                time_product(cfg.curve, cfg.curve.label(), 1, n, count, || {
+                    hilbert_matrix_vector_product(&flattened_A, &v, &mut output, &coordinates)
+                })
+            }
+        };
+        rows.push(row);
+    }
+    rows
+}
+
+/// Thread scaling of the Hilbert curve: for every count in `threads`, time
+/// `hilbert_matrix_vector_product_parallel` (label `hilbert_parallel`) and
+/// `hilbert_coordinates_parallel` (label `hilbert_coordinates_parallel`) on a
+/// pool of that many threads, for every size in `cfg.sizes`.
+///
+/// `cfg.curve` and `cfg.block` are ignored.
+#[cfg(all(feature = "cli", feature = "rayon"))]
+pub fn run_scaling(cfg: &ExperimentConfig, threads: &[usize]) -> eyre::Result<Vec<ExperimentRow>> {
+    let curve = CurveKind::Hilbert;
+    let count = cfg.repetitions;
+    let mut rows = Vec::with_capacity(2 * threads.len() * cfg.sizes.len());
+    for &t in threads {
+        let pool = thread_pool(Some(t))?;
+        let t = pool.current_num_threads();
+        // The same inputs for every thread count.
+        let mut rng = seeded_rng(cfg.seed);
+        for &n in &cfg.sizes {
+            #[allow(non_snake_case)]
+            let (A, v) = setup_inputs(n, &mut rng);
+            #[allow(non_snake_case)]
+            let (coordinates, flattened_A) = setup_hilbert(n, A);
+            let mut output = vec![0; n];
+            pool.install(|| {
+                rows.push(time_product(curve, "hilbert_parallel", t, n, count, || {
+                    hilbert_matrix_vector_product_parallel(
+                        &flattened_A,
+                        &v,
+                        &mut output,
+                        &coordinates,
+                    )
+                }));
+                rows.push(time_product(
+                    curve,
+                    "hilbert_coordinates_parallel",
+                    t,
+                    n,
+                    count,
+                    || {
+                        core::hint::black_box(hilbert_coordinates_parallel(padded_order(n)));
+                    },
+                ));
+            });
+        }
+    }
+    Ok(rows)
+}
+
+#[cfg(feature = "cli")]
+fn time_product<F: FnMut()>(
+    curve: CurveKind,
+    label: &'static str,
+    threads: usize,
+    n: usize,
+    count: usize,
+    mut product: F,
+) -> ExperimentRow {
+    let seconds = timeit::timeit_loops! {count, { product(); }};
+    #[cfg(perf_counters)]
+    let counters = count_perf(count, &mut product).ok();
+    ExperimentRow {
+        curve,
+        label,
+        threads,
+        n,
+        seconds,
+        #[cfg(perf_counters)]
+        counters,
+    }
+}
+
+/// Write `rows` as CSV with the header
+/// `label,threads,n,seconds,cycles,branches,missed_branches,instructions`.
+///
+/// The counter columns are left empty unless the counters were measured
+/// (`macos-perf` or `linux-perf`, and readable), so the columns are the same
+/// either way.
+#[cfg(feature = "cli")]
+pub fn write_csv<W: std::io::Write>(rows: &[ExperimentRow], w: &mut W) -> std::io::Result<()> {
+    writeln!(
+        w,
+        "label,threads,n,seconds,cycles,branches,missed_branches,instructions"
+    )?;
+    for row in rows {
+        write!(w, "{},{},{},{}", row.label, row.threads, row.n, row.seconds)?;
+        #[cfg(perf_counters)]
+        match &row.counters {
+            Some(counters) => writeln!(
+                w,
+                ",{},{},{},{}",
+                counters.cycles, counters.branches, counters.missed_branches, counters.instructions
+            )?,
+            None => writeln!(w, ",,,,")?,
+        }
+        #[cfg(not(perf_counters))]
+        writeln!(w, ",,,,")?;
+    }
+    Ok(())
+}
+
+// The tests generate their inputs with the `cli` helpers.
+#[cfg(all(test, feature = "cli"))]
+mod test {
+    use std::sync::Arc;
+    use std::time::{self};
+
+    use insta::assert_yaml_snapshot;
+    use proptest::prelude::*;
+    use rand::distributions::Uniform;
+    use rand::Rng;
+    use rand_chacha::{rand_core::SeedableRng, ChaCha8Rng};
+    use timeit::timeit_loops;
+
+    use crate::{
+        access_recency_grid, blocked_matrix_vector_product, cached_coordinates, constant_matrix,
+        curve_to_svg, diagonal_matrix, direction_histogram, first_divergence, flat_index,
+        flatten_adjacency_distance, flatten_matrix, flatten_matrix_into, flatten_matrix_recursive,
+        flatten_nested, flatten_two_matrices, hilbert_blocks, hilbert_flat_permutation,
+        hilbert_matrix_matrix_product, hilbert_matrix_vector_product,
+        hilbert_matrix_vector_product_dense, hilbert_matrix_vector_product_iter,
+        hilbert_matrix_vector_product_recursive, hilbert_matrix_vector_product_soa,
+        hilbert_matrix_vector_product_streaming, hilbert_matrix_vector_product_unchecked,
+        hilbert_matrix_vector_product_windowed, hilbert_permutation, hilbert_permutation_inverse,
+        hilbert_product_rows, hilbert_product_with_checksum, hilbert_range_bbox,
+        hilbert_reduce_rows, hilbert_sum_product, hilbert_to_xy, hilbert_to_xy_lut,
+        hilbert_transpose, identity_matrix, is_power_of_two, is_valid_hilbert, load_matrix_csv,
+        locality_stats, log2, log2_exact, make_matrix, make_matrix_with, matrix_with_locality,
+        morton_decode, morton_encode, morton_iter, naive_matrix_matrix_product,
+        naive_matrix_vector_product, naive_matrix_vector_product_checked,
+        naive_matrix_vector_product_colmajor, naive_matrix_vector_product_nested, naive_transpose,
+        nest_matrix, order_of, padded_order, peano_iter, product_may_overflow, product_stats,
+        run_experiment, seeded_rng, setup_curve, snake_iter, tile_plan, transpose_permutation,
+        unflatten_matrix, validate_curve, vector_checksum, write_csv, xy_to_hilbert,
+        xy_to_hilbert_graycode, xy_to_hilbert_lut, ColumnMajor, CoordinateTable, Coordinates,
+        CurveError, CurveKind, CurveMetrics, DenseMatrix, ExperimentConfig, FnMatrix, HilbertCurve,
+        HilbertError, HilbertIter, OutputStats, ReuseDistanceCache, DEFAULT_WINDOW_ORDER,
+        LOCALITY_BLOCK, LUT_MAX_ORDER, MAX_DEPTH, METRICS_WINDOW,
+    };
+
+    #[test]
+    fn test_equality_of_implementation() {
+        let mut rng = ChaCha8Rng::seed_from_u64(10);
+
+        let range = Uniform::new(1, 11);
+        let n: usize = 2usize.pow(8);
+
+        let start = time::Instant::now();
+        // A = [[random.randint(1, 10) for _ in range(n)] for _ in range(n)]
+        #[allow(non_snake_case)]
+        let A = make_matrix(n, 1, 11, &mut rng);
+        // v = [random.randint(1, 10) for _ in range(n)]
+        let v: Vec<_> = (0..n).map(|_| rng.sample(range)).collect();
+        assert_eq!(v.len(), n);
+        let mut output1 = vec![0; n];
         let mut output2 = vec![0; n];
         let mut output3 = vec![0; n];
         let end = time::Instant::now();
         println!("Initial data generation: {}s", (end - start).as_secs_f32());
 
-        // Naive
-        let timeit_count = 3;
-        let _ = timeit_loops! {timeit_count,
-            {  naive_matrix_vector_product(&A, &v, &mut output1, n); }
-        };
+        // Naive
+        let timeit_count = 3;
+        let _ = timeit_loops! {timeit_count,
+            {  naive_matrix_vector_product(&A, &v, &mut output1, n); }
+        };
+
+        // reorder data
+        #[allow(non_snake_case)]
+        let (depth, flattened_A) = super::setup_hilbert_iter(n, A.clone());
+
+        // Hilbert Product
+        let _ = timeit_loops! {timeit_count,
+            {hilbert_matrix_vector_product_iter(&flattened_A,&v, &mut output2, depth);}
+        };
+
+        // non-iterative version
+        let (hilbert_iter, flattened_a) = super::setup_hilbert(n, A);
+
+        let _ = timeit_loops! {timeit_count,
+            {hilbert_matrix_vector_product(&flattened_a,&v, &mut output3, &hilbert_iter);}
+        };
+
+        assert_eq!(output1, output2);
+        assert_eq!(output1, output3);
+        assert_yaml_snapshot!(output2);
+    }
+
+    #[test]
+    fn test_transpose_permutation() {
+        let mut rng = ChaCha8Rng::seed_from_u64(10);
+        let n: usize = 2usize.pow(4);
+        #[allow(non_snake_case)]
+        let A = make_matrix(n, 1, 11, &mut rng);
+        let mut transposed = vec![0; n * n];
+        for i in 0..n {
+            for j in 0..n {
+                transposed[flat_index(j, i, n)] = A[flat_index(i, j, n)];
+            }
+        }
+
+        let (coordinate_iter, flattened_a) = super::setup_hilbert(n, A);
+        let (_, flattened_transposed) = super::setup_hilbert(n, transposed);
+
+        let perm = transpose_permutation(&coordinate_iter, n);
+        let permuted: Vec<_> = perm.iter().map(|&t| flattened_a[t]).collect();
+        assert_eq!(permuted, flattened_transposed);
+    }
+
+    #[test]
+    fn test_setup_hilbert_reports_both_sizes() {
+        let result = std::panic::catch_unwind(|| super::setup_hilbert(4, vec![0; 15]));
+        let payload = result.unwrap_err();
+        let message = payload.downcast_ref::<String>().unwrap();
+        assert!(message.contains("16 elements"), "{}", message);
+        assert!(message.contains("got 15 elements"), "{}", message);
+    }
+
+    #[test]
+    #[should_panic(expected = "n = 6 is not 2^3, the side of the curve")]
+    fn test_hilbert_flat_permutation_rejects_mismatched_n() {
+        hilbert_flat_permutation(3, 6);
+    }
+
+    #[test]
+    fn test_hilbert_flat_permutation() {
+        let order = 5;
+        let n = 2usize.pow(order as u32);
+        let perm = hilbert_flat_permutation(order, n);
+
+        let mut sorted = perm.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, (0..n * n).collect::<Vec<_>>());
+
+        let expected: Vec<_> = HilbertIter::new(order)
+            .map(|(_, (i, j))| flat_index(i, j, n))
+            .collect();
+        assert_eq!(perm, expected);
+    }
+
+    #[test]
+    fn test_snake_iter_is_bijection() {
+        let n = 7;
+        let snake: Vec<_> = snake_iter(n).collect();
+        let mut seen = vec![false; n * n];
+        for (t, (t_expected, (i, j))) in snake.iter().enumerate() {
+            assert_eq!(t, *t_expected);
+            assert!(!seen[flat_index(*i, *j, n)]);
+            seen[flat_index(*i, *j, n)] = true;
+        }
+        assert!(seen.iter().all(|&s| s));
+    }
+
+    #[test]
+    fn test_snake_product() {
+        let mut rng = ChaCha8Rng::seed_from_u64(10);
+        let n = 12;
+        #[allow(non_snake_case)]
+        let (A, v) = super::setup_inputs(n, &mut rng);
+        let mut expected = vec![0; n];
+        naive_matrix_vector_product(&A, &v, &mut expected, n);
+
+        let (snake, flattened_a) = super::setup_snake(n, A);
+        let mut output = vec![0; n];
+        hilbert_matrix_vector_product(&flattened_a, &v, &mut output, &snake);
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn test_hilbert_curve_matvec() {
+        let mut rng = ChaCha8Rng::seed_from_u64(10);
+        let curve = HilbertCurve::new(5);
+        let n = curve.n();
+        for _ in 0..2 {
+            #[allow(non_snake_case)]
+            let (A, v) = super::setup_inputs(n, &mut rng);
+            let mut expected = vec![0; n];
+            naive_matrix_vector_product(&A, &v, &mut expected, n);
+            assert_eq!(curve.matvec(&A, &v), expected);
+        }
+    }
+
+    #[test]
+    fn test_product_may_overflow() {
+        // setup_inputs draws entries up to 10, so every term is at most 100.
+        assert!(!product_may_overflow(2usize.pow(14), 10));
+        assert!(!product_may_overflow(i32::MAX as usize / 100, 10));
+        assert!(product_may_overflow(i32::MAX as usize / 100 + 1, 10));
+        assert!(product_may_overflow(2usize.pow(11), i32::MAX / 1000));
+    }
+
+    #[test]
+    fn test_first_divergence() {
+        let a = [1, 2, 3, 4, 5];
+        assert_eq!(first_divergence(&a, &a), None);
+        assert_eq!(first_divergence(&a, &[1, 2, 3, 7, 9]), Some((3, 4, 7)));
+    }
+
+    #[test]
+    fn test_load_matrix_csv() {
+        let csv = "1, 2, 3\n4,5,6\n7,8,-9\n";
+        let (data, n) = load_matrix_csv(csv.as_bytes()).unwrap();
+        assert_eq!(n, 3);
+        assert_eq!(data, vec![1, 2, 3, 4, 5, 6, 7, 8, -9]);
+    }
+
+    #[test]
+    fn test_load_matrix_csv_rejects_ragged_rows() {
+        let error = load_matrix_csv("1,2\n3\n".as_bytes()).unwrap_err();
+        assert!(error.to_string().contains("line 2"), "{}", error);
+        assert!(load_matrix_csv("1,2\n3,4\n5,6\n".as_bytes()).is_err());
+        assert!(load_matrix_csv("1,x\n3,4\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_curve_metrics_ordering() {
+        let order = 5;
+        let n = 2usize.pow(order as u32);
+        let hilbert: Vec<_> = HilbertIter::new(order).collect();
+        let snake: Vec<_> = snake_iter(n).collect();
+        let row_major: Vec<_> = (0..n * n).map(|t| (t, (t / n, t % n))).collect();
+
+        let mut metrics = [
+            ("row-major", CurveMetrics::new(&row_major, METRICS_WINDOW)),
+            ("snake", CurveMetrics::new(&snake, METRICS_WINDOW)),
+            ("hilbert", CurveMetrics::new(&hilbert, METRICS_WINDOW)),
+        ];
+        metrics.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+        let ranking: Vec<_> = metrics.iter().map(|(name, _)| *name).collect();
+        assert_eq!(ranking, ["hilbert", "snake", "row-major"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "a window holds at least one cell")]
+    fn test_curve_metrics_rejects_zero_window() {
+        CurveMetrics::new(&HilbertIter::new(1).collect::<Vec<_>>(), 0);
+    }
+
+    #[test]
+    fn test_flatten_adjacency_distance() {
+        let order = 6;
+        let n = 2usize.pow(order as u32);
+        let hilbert: Vec<_> = HilbertIter::new(order).collect();
+        let row_major: Vec<_> = (0..n * n).map(|t| (t, (t / n, t % n))).collect();
+        assert!(flatten_adjacency_distance(&hilbert) < 1.1);
+        assert!(flatten_adjacency_distance(&row_major) > 1.5);
+    }
+
+    #[test]
+    fn test_flatten_matrix_recursive() {
+        let mut rng = ChaCha8Rng::seed_from_u64(10);
+        for depth in 0..6 {
+            let n = 2usize.pow(depth as u32);
+            #[allow(non_snake_case)]
+            let (A, v) = super::setup_inputs(n, &mut rng);
+            let flattened = flatten_matrix_recursive(&A, n);
+            assert_eq!(flattened, flatten_matrix(depth, A.clone(), n));
+
+            let mut expected = vec![0; n];
+            naive_matrix_vector_product(&A, &v, &mut expected, n);
+            let mut output = vec![0; n];
+            hilbert_matrix_vector_product_recursive(&flattened, &v, &mut output, n);
+            assert_eq!(output, expected);
+        }
+    }
+
+    #[test]
+    fn test_hilbert_product_rows() {
+        let mut rng = ChaCha8Rng::seed_from_u64(10);
+        let n = 2usize.pow(5);
+        #[allow(non_snake_case)]
+        let (A, v) = super::setup_inputs(n, &mut rng);
+        let (coordinate_iter, flattened_a) = super::setup_hilbert(n, A);
+        let mut expected = vec![0; n];
+        hilbert_matrix_vector_product(&flattened_a, &v, &mut expected, &coordinate_iter);
+
+        let bounds = [0, 5, 6, 20, n];
+        let mut output = Vec::new();
+        for rows in bounds.windows(2) {
+            let mut part = vec![0; rows[1] - rows[0]];
+            hilbert_product_rows(
+                &flattened_a,
+                &v,
+                &mut part,
+                &coordinate_iter,
+                rows[0],
+                rows[1],
+            );
+            output.extend(part);
+        }
+        assert_eq!(output, expected);
+    }
+
+    const _: () = assert!(order_of(256) == 8);
+    const _: () = assert!(is_power_of_two(1) && !is_power_of_two(0) && !is_power_of_two(12));
+
+    #[test]
+    fn test_order_of() {
+        let table = [0u8; order_of(1024)];
+        assert_eq!(table.len(), 10);
+        for k in 0..usize::BITS as usize {
+            assert!(is_power_of_two(1 << k));
+            assert_eq!(order_of(1 << k), k);
+        }
+        assert!(!is_power_of_two(1000));
+    }
+
+    #[test]
+    fn test_naive_matrix_vector_product_colmajor() {
+        let mut rng = ChaCha8Rng::seed_from_u64(10);
+        for n in [1, 3, 16, 33] {
+            #[allow(non_snake_case)]
+            let (A, v) = super::setup_inputs(n, &mut rng);
+            let mut expected = vec![0; n];
+            naive_matrix_vector_product(&A, &v, &mut expected, n);
+            let mut output = vec![0; n];
+            naive_matrix_vector_product_colmajor(&A, &v, &mut output, n);
+            assert_eq!(output, expected);
+        }
+    }
+
+    #[test]
+    fn test_seeded_rng_is_reproducible() {
+        let mut a = seeded_rng(42);
+        let mut b = seeded_rng(42);
+        let xs: Vec<u64> = (0..16).map(|_| a.gen()).collect();
+        let ys: Vec<u64> = (0..16).map(|_| b.gen()).collect();
+        assert_eq!(xs, ys);
+        assert_ne!(
+            xs,
+            (0..16).map(|_| seeded_rng(43).gen()).collect::<Vec<u64>>()
+        );
+    }
+
+    #[test]
+    fn test_reuse_distance_cache() {
+        let mut cache = ReuseDistanceCache::new(2);
+        assert_eq!(cache.access(1), None);
+        assert_eq!(cache.access(2), None);
+        assert_eq!(cache.access(1), Some(1));
+        assert_eq!(cache.access(1), Some(0));
+        assert_eq!(cache.access(3), None);
+        // 2 was the least recently used line and got evicted.
+        assert_eq!(cache.access(2), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "a cache needs room for at least one line")]
+    fn test_reuse_distance_cache_rejects_zero_capacity() {
+        ReuseDistanceCache::new(0);
+    }
+
+    #[test]
+    #[should_panic(expected = "a cache line holds at least one element")]
+    fn test_access_recency_grid_rejects_zero_cache_line() {
+        access_recency_grid(&HilbertIter::new(1).collect::<Vec<_>>(), 2, 0);
+    }
+
+    #[test]
+    fn test_access_recency_grid() {
+        let order = 5;
+        let n = 2usize.pow(order as u32);
+        let hilbert: Vec<_> = HilbertIter::new(order).collect();
+        let row_major: Vec<_> = (0..n * n).map(|t| (t, (t / n, t % n))).collect();
+
+        let hilbert_grid = access_recency_grid(&hilbert, n, 4);
+        assert_eq!(hilbert_grid.len(), n);
+        assert!(hilbert_grid.iter().all(|row| row.len() == n));
+        assert_eq!(hilbert_grid[0][0], u8::MAX);
+
+        let total = |grid: &[Vec<u8>]| grid.iter().flatten().map(|&d| d as usize).sum::<usize>();
+        let row_major_grid = access_recency_grid(&row_major, n, 4);
+        assert!(total(&hilbert_grid) < total(&row_major_grid));
+    }
+
+    #[test]
+    fn test_hilbert_curve_try_from() {
+        let points: Vec<_> = HilbertIter::new(3).collect();
+        assert!(is_valid_hilbert(&points));
+        let curve = HilbertCurve::try_from(points.clone()).unwrap();
+        assert_eq!(curve.order(), 3);
+        assert_eq!(curve.coordinates(), &points[..]);
+
+        let mut corrupt = points.clone();
+        corrupt.swap(10, 11);
+        assert!(!is_valid_hilbert(&corrupt));
+        let result: Result<HilbertCurve, _> = corrupt.try_into();
+        assert_eq!(result.err(), Some(CurveError::Mismatch { index: 10 }));
+
+        let result = HilbertCurve::try_from(points[..8].to_vec());
+        assert_eq!(result.err(), Some(CurveError::InvalidLength(8)));
+    }
+
+    #[test]
+    fn test_locality_stats_snapshots() {
+        for order in 1..=4 {
+            insta::assert_debug_snapshot!(
+                format!("locality_stats_order_{}", order),
+                locality_stats(order)
+            );
+        }
+    }
+
+    #[test]
+    fn test_hilbert_sum_product() {
+        let mut rng = ChaCha8Rng::seed_from_u64(10);
+        let n = 2usize.pow(5);
+        #[allow(non_snake_case)]
+        let (A, v) = super::setup_inputs(n, &mut rng);
+        #[allow(non_snake_case)]
+        let B = make_matrix(n, -5, 5, &mut rng);
+
+        let mut expected = vec![0; n];
+        naive_matrix_vector_product(&A, &v, &mut expected, n);
+        naive_matrix_vector_product(&B, &v, &mut expected, n);
+
+        let coordinate_iter: Vec<_> = HilbertIter::new(5).collect();
+        let (flattened_a, flattened_b) = flatten_two_matrices(&A, &B, &coordinate_iter, n);
+        assert_eq!(flattened_a, flatten_matrix(5, A, n));
+        let mut output = vec![0; n];
+        hilbert_sum_product(
+            &flattened_a,
+            &flattened_b,
+            &v,
+            &mut output,
+            &coordinate_iter,
+        );
+        assert_eq!(output, expected);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_thread_pool() {
+        let mut rng = ChaCha8Rng::seed_from_u64(10);
+        let n = 2usize.pow(6);
+        #[allow(non_snake_case)]
+        let (A, v) = super::setup_inputs(n, &mut rng);
+        #[allow(non_snake_case)]
+        let (coordinate_iter, flattened_A) = super::setup_hilbert(n, A);
+        let mut expected = vec![0; n];
+        hilbert_matrix_vector_product(&flattened_A, &v, &mut expected, &coordinate_iter);
+
+        for threads in [1, 2, 3, 4] {
+            let pool = crate::thread_pool(Some(threads)).unwrap();
+            assert_eq!(pool.current_num_threads(), threads);
+            let (output, coordinates) = pool.install(|| {
+                let mut output = vec![0; n];
+                crate::hilbert_matrix_vector_product_parallel(
+                    &flattened_A,
+                    &v,
+                    &mut output,
+                    &coordinate_iter,
+                );
+                (output, crate::hilbert_coordinates_parallel(6))
+            });
+            assert_eq!(output, expected, "{} threads", threads);
+            assert_eq!(coordinates, coordinate_iter, "{} threads", threads);
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_run_scaling() {
+        let cfg = ExperimentConfig::builder()
+            .sizes([4, 16])
+            .repetitions(1)
+            .build();
+        let rows = crate::run_scaling(&cfg, &[1, 2]).unwrap();
+        let summary: Vec<_> = rows
+            .iter()
+            .map(|row| (row.label, row.threads, row.n))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("hilbert_parallel", 1, 4),
+                ("hilbert_coordinates_parallel", 1, 4),
+                ("hilbert_parallel", 1, 16),
+                ("hilbert_coordinates_parallel", 1, 16),
+                ("hilbert_parallel", 2, 4),
+                ("hilbert_coordinates_parallel", 2, 4),
+                ("hilbert_parallel", 2, 16),
+                ("hilbert_coordinates_parallel", 2, 16),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_hilbert_curve_extend_to() {
+        for start in 0..3 {
+            let mut curve = HilbertCurve::new(start);
+            for order in start..7 {
+                curve.extend_to(order);
+                assert_eq!(curve.order(), order);
+                assert_eq!(curve.coordinates(), HilbertCurve::new(order).coordinates());
+            }
+        }
+    }
+
+    #[test]
+    fn test_wrapping_products() {
+        use std::num::Wrapping;
+
+        let n = 2usize.pow(3);
+        let a: Vec<i32> = (0..n * n).map(|k| i32::MAX - k as i32).collect();
+        let v: Vec<i32> = (0..n).map(|j| 3 + j as i32).collect();
+        let mut expected = vec![0i32; n];
+        for i in 0..n {
+            for j in 0..n {
+                expected[i] = expected[i].wrapping_add(a[flat_index(i, j, n)].wrapping_mul(v[j]));
+            }
+        }
+
+        let wrapped_a: Vec<_> = a.iter().copied().map(Wrapping).collect();
+        let wrapped_v: Vec<_> = v.iter().copied().map(Wrapping).collect();
+        let mut naive = vec![Wrapping(0); n];
+        naive_matrix_vector_product(&wrapped_a, &wrapped_v, &mut naive, n);
+        assert_eq!(naive.iter().map(|x| x.0).collect::<Vec<_>>(), expected);
+
+        let coordinate_iter: Vec<_> = HilbertIter::new(3).collect();
+        let mut flattened = vec![Wrapping(0); n * n];
+        for (t, (i, j)) in &coordinate_iter {
+            flattened[*t] = wrapped_a[flat_index(*i, *j, n)];
+        }
+        let mut hilbert = vec![Wrapping(0); n];
+        hilbert_matrix_vector_product(&flattened, &wrapped_v, &mut hilbert, &coordinate_iter);
+        assert_eq!(hilbert, naive);
+    }
+
+    #[test]
+    fn test_saturating_product() {
+        use std::num::Saturating;
+
+        let n = 4;
+        let a = vec![Saturating(i32::MAX / 2); n * n];
+        let v = vec![Saturating(3); n];
+        let mut output = vec![Saturating(0); n];
+        naive_matrix_vector_product(&a, &v, &mut output, n);
+        assert!(output.iter().all(|x| x.0 == i32::MAX));
+    }
+
+    #[test]
+    fn test_direction_histogram() {
+        assert_eq!(direction_histogram(0), [0, 0, 0, 0]);
+        assert_eq!(direction_histogram(1), [1, 1, 0, 1]);
+        for order in 1..8 {
+            let n = 2usize.pow(order as u32);
+            let [up, down, left, right] = direction_histogram(order);
+            assert_eq!(up + down + left + right, n * n - 1);
+            assert_eq!(up, down);
+            assert_eq!(up, n * n / 4);
+            assert_eq!(right - left, n - 1);
+        }
+    }
+
+    #[test]
+    fn test_naive_matrix_vector_product_nested() {
+        let mut rng = ChaCha8Rng::seed_from_u64(10);
+        for n in [1, 5, 32] {
+            #[allow(non_snake_case)]
+            let (A, v) = super::setup_inputs(n, &mut rng);
+            let nested: Vec<Vec<i32>> = A.chunks(n).map(|row| row.to_vec()).collect();
+            let mut expected = vec![0; n];
+            naive_matrix_vector_product(&A, &v, &mut expected, n);
+            let mut output = vec![0; n];
+            naive_matrix_vector_product_nested(&nested, &v, &mut output);
+            assert_eq!(output, expected);
+        }
+    }
+
+    #[test]
+    fn test_matrix_with_locality() {
+        let n = 20;
+        let random = make_matrix(n, 1, 11, &mut ChaCha8Rng::seed_from_u64(3));
+        let uncorrelated = matrix_with_locality(n, 0.0, &mut ChaCha8Rng::seed_from_u64(3));
+        assert_eq!(uncorrelated, random);
+
+        let blocky = matrix_with_locality(n, 1.0, &mut ChaCha8Rng::seed_from_u64(3));
+        for i in 0..n {
+            for j in 0..n {
+                let corner = (
+                    i / LOCALITY_BLOCK * LOCALITY_BLOCK,
+                    j / LOCALITY_BLOCK * LOCALITY_BLOCK,
+                );
+                assert_eq!(
+                    blocky[flat_index(i, j, n)],
+                    blocky[flat_index(corner.0, corner.1, n)]
+                );
+            }
+        }
+
+        let mixed = matrix_with_locality(n, 0.5, &mut ChaCha8Rng::seed_from_u64(3));
+        assert!(mixed.iter().all(|x| (1..=10).contains(x)));
+    }
+
+    #[cfg(feature = "arrow")]
+    #[test]
+    fn test_write_curve_parquet() {
+        use arrow_array::{cast::AsArray, types::UInt64Type};
+        use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+        let path = std::env::temp_dir().join(format!("hilbert-{}.parquet", std::process::id()));
+        crate::write_curve_parquet(4, &path).unwrap();
+
+        let file = std::fs::File::open(&path).unwrap();
+        let reader = ParquetRecordBatchReaderBuilder::try_new(file)
+            .unwrap()
+            .build()
+            .unwrap();
+        let mut points = Vec::new();
+        for batch in reader {
+            let batch = batch.unwrap();
+            let column = |name: &str| {
+                batch
+                    .column_by_name(name)
+                    .unwrap()
+                    .as_primitive::<UInt64Type>()
+                    .clone()
+            };
+            let (index, i, j) = (column("index"), column("i"), column("j"));
+            for row in 0..batch.num_rows() {
+                points.push((
+                    index.value(row) as usize,
+                    (i.value(row) as usize, j.value(row) as usize),
+                ));
+            }
+        }
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(points, HilbertIter::new(4).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_hilbert_reduce_rows() {
+        let mut rng = ChaCha8Rng::seed_from_u64(10);
+        let n = 2usize.pow(4);
+        #[allow(non_snake_case)]
+        let A = make_matrix(n, -20, 20, &mut rng);
+        let row_sums: Vec<i64> = A
+            .chunks(n)
+            .map(|row| row.iter().map(|&x| x as i64).sum())
+            .collect();
+        let row_max_abs: Vec<i32> = A
+            .chunks(n)
+            .map(|row| row.iter().map(|x| x.abs()).max().unwrap())
+            .collect();
+
+        let (coordinate_iter, flattened_a) = super::setup_hilbert(n, A);
+        let sums = hilbert_reduce_rows(&flattened_a, &coordinate_iter, n, 0i64, |acc, x| {
+            acc + x as i64
+        });
+        assert_eq!(sums, row_sums);
+        let max_abs = hilbert_reduce_rows(&flattened_a, &coordinate_iter, n, 0, |acc, x| {
+            acc.max(x.abs())
+        });
+        assert_eq!(max_abs, row_max_abs);
+    }
+
+    #[test]
+    fn test_depth_zero() {
+        let points: Vec<_> = HilbertIter::new(0).collect();
+        assert_eq!(points, vec![(0, (0, 0))]);
+
+        let (coordinate_iter, flattened_a) = super::setup_hilbert(1, vec![5]);
+        assert_eq!(coordinate_iter, points);
+        assert_eq!(flattened_a, vec![5]);
+        let mut output = vec![1];
+        hilbert_matrix_vector_product(&flattened_a, &[3], &mut output, &coordinate_iter);
+        assert_eq!(output, vec![16]);
+
+        let (depth, flattened_a) = super::setup_hilbert_iter(1, vec![5]);
+        assert_eq!(depth, 0);
+        let mut output = vec![0];
+        hilbert_matrix_vector_product_iter(&flattened_a, &[3], &mut output, depth);
+        assert_eq!(output, vec![15]);
+    }
+
+    #[test]
+    fn test_flatten_nested_round_trip() {
+        let nested = vec![vec![1, 2, 3], vec![4, 5, 6], vec![7, 8, 9]];
+        let flat = flatten_nested(&nested);
+        assert_eq!(flat, (1..=9).collect::<Vec<_>>());
+        assert_eq!(nest_matrix(&flat, 3), nested);
+        assert!(nest_matrix::<i32>(&[], 0).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "row 1 has 2 elements, expected 3")]
+    fn test_flatten_nested_rejects_jagged() {
+        flatten_nested(&[vec![1, 2, 3], vec![4, 5], vec![7, 8, 9]]);
+    }
+
+    #[cfg(feature = "core_affinity")]
+    #[test]
+    fn test_pin_to_core() {
+        // Pinning may be unsupported (or restricted) on the host; it must
+        // then fail gracefully rather than panic.
+        let pinned = std::thread::spawn(|| crate::pin_to_core(0)).join().unwrap();
+        if core_affinity::get_core_ids().is_some_and(|ids| !ids.is_empty()) {
+            assert!(pinned);
+        }
+        assert!(!crate::pin_to_core(usize::MAX));
+
+        // The flag handling on top: an unavailable core only warns.
+        std::thread::spawn(|| {
+            crate::pin_core(None).unwrap();
+            assert!(!crate::pin_core(Some(usize::MAX)).unwrap());
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[cfg(not(feature = "core_affinity"))]
+    #[test]
+    fn test_pin_core_requires_feature() {
+        assert!(!crate::pin_core(None).unwrap());
+        let err = crate::pin_core(Some(0)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "--core requires the `core_affinity` feature"
+        );
+    }
+
+    #[test]
+    fn test_tile_plan() {
+        let order = 4;
+        let coordinate_iter: Vec<_> = HilbertIter::new(order).collect();
+        for tile_elems in [1, 7, 16, 256, 1000] {
+            let plan = tile_plan(order, tile_elems);
+            let mut next = 0;
+            for tile in &plan {
+                assert_eq!(tile.range.start, next);
+                assert!(!tile.range.is_empty() && tile.range.len() <= tile_elems);
+                next = tile.range.end;
+                for (_, (i, j)) in &coordinate_iter[tile.range.clone()] {
+                    assert!((tile.min.0..=tile.max.0).contains(i));
+                    assert!((tile.min.1..=tile.max.1).contains(j));
+                }
+            }
+            assert_eq!(next, coordinate_iter.len());
+        }
+        // Aligned tiles of 4^k cells are 2^k x 2^k squares.
+        let tile = &tile_plan(order, 16)[5];
+        assert_eq!((tile.max.0 - tile.min.0, tile.max.1 - tile.min.1), (3, 3));
+    }
+
+    #[test]
+    fn test_hilbert_iter_with_offset() {
+        let offset = (100, 200);
+        for depth in 0..5 {
+            let plain: Vec<_> = HilbertIter::new(depth).collect();
+            let shifted: Vec<_> = HilbertIter::with_offset(depth, offset).collect();
+            assert_eq!(plain.len(), shifted.len());
+            for ((t, (i, j)), (s, (k, l))) in plain.into_iter().zip(shifted) {
+                assert_eq!(t, s);
+                assert_eq!((i + offset.0, j + offset.1), (k, l));
+            }
+        }
+    }
+
+    #[test]
+    fn test_hilbert_product_with_checksum() {
+        let n = 64;
+        let mut rng = ChaCha8Rng::seed_from_u64(10);
+        #[allow(non_snake_case)]
+        let (A, v) = super::setup_inputs(n, &mut rng);
+        let mut expected = vec![0; n];
+        naive_matrix_vector_product(&A, &v, &mut expected, n);
+        #[allow(non_snake_case)]
+        let (coordinate_iter, flattened_A) = super::setup_hilbert(n, A);
+
+        let (output, checksum) = hilbert_product_with_checksum(&flattened_A, &v, &coordinate_iter);
+        assert_eq!(output, expected);
+        assert_eq!(checksum, vector_checksum(&output));
+
+        // Swapping two entries changes the checksum.
+        let mut swapped = output.clone();
+        swapped.swap(0, 1);
+        assert_ne!(vector_checksum(&swapped), checksum);
+        assert_eq!(vector_checksum(&[-1, 2]), 3);
+    }
+
+    #[test]
+    fn test_hilbert_iter_last() {
+        for order in 0..=8 {
+            let collected = HilbertIter::new(order).collect::<Vec<_>>();
+            assert_eq!(HilbertIter::new(order).last(), collected.last().copied());
+        }
+        assert_eq!(
+            HilbertIter::with_offset(2, (100, 200)).last(),
+            Some((15, (100, 203)))
+        );
+
+        let mut iter = HilbertIter::new(1);
+        iter.by_ref().take(3).for_each(drop);
+        assert_eq!(iter.last(), Some((3, (0, 1))));
+        let mut iter = HilbertIter::new(1);
+        iter.by_ref().take(4).for_each(drop);
+        assert_eq!(iter.last(), None);
+    }
+
+    #[test]
+    fn test_hilbert_product_unchecked() {
+        let n = 64;
+        let mut rng = ChaCha8Rng::seed_from_u64(10);
+        #[allow(non_snake_case)]
+        let (A, v) = super::setup_inputs(n, &mut rng);
+        #[allow(non_snake_case)]
+        let (coordinate_iter, flattened_A) = super::setup_hilbert(n, A);
+        let mut expected = vec![0; n];
+        hilbert_matrix_vector_product(&flattened_A, &v, &mut expected, &coordinate_iter);
+        let mut output = vec![0; n];
+        // Safety: the coordinates come from `setup_hilbert` for this `n`.
+        unsafe {
+            hilbert_matrix_vector_product_unchecked(&flattened_A, &v, &mut output, &coordinate_iter)
+        };
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn test_product_stats() {
+        assert_eq!(
+            product_stats(&[3, -1, 4, 1, 5, -9, 2, 6]),
+            OutputStats {
+                min: -9,
+                max: 6,
+                mean: 1.375,
+                sum: 11
+            }
+        );
+        // The sum doesn't overflow where the entries would.
+        let stats = product_stats(&[i32::MAX, i32::MAX]);
+        assert_eq!(stats.sum, 2 * i32::MAX as i64);
+        assert_eq!(stats.mean, i32::MAX as f64);
+    }
+
+    #[test]
+    fn test_xy_to_hilbert() {
+        for order in 0..=6 {
+            for (t, (i, j)) in HilbertIter::new(order) {
+                assert_eq!(xy_to_hilbert(i, j, order), t);
+            }
+        }
+    }
+
+    #[test]
+    fn test_xy_to_hilbert_graycode_matches_grammar() {
+        for order in 0..=7 {
+            for (t, (i, j)) in HilbertIter::new(order) {
+                assert_eq!(
+                    xy_to_hilbert_graycode(i, j, order),
+                    t,
+                    "cell ({}, {}) at order {}",
+                    i,
+                    j,
+                    order
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_hilbert_to_xy() {
+        for order in 0..=6 {
+            let n = 1 << order;
+            for (t, (i, j)) in HilbertIter::new(order) {
+                assert_eq!(hilbert_to_xy(t, order), (i, j));
+            }
+            for x in 0..n {
+                for y in 0..n {
+                    assert_eq!(hilbert_to_xy(xy_to_hilbert(x, y, order), order), (x, y));
+                }
+            }
+            assert_eq!(hilbert_to_xy(0, order), (0, 0));
+            assert_eq!(hilbert_to_xy(n * n - 1, order), (0, n - 1));
+        }
+    }
+
+    /// Assert `a` and `b` agree to within `eps` at every index, reporting the
+    /// first index and size of a larger difference.
+    #[track_caller]
+    fn assert_vectors_close(a: &[f64], b: &[f64], eps: f64) {
+        assert_eq!(a.len(), b.len(), "the vectors have different lengths");
+        if let Some((index, (x, y))) = a
+            .iter()
+            .zip(b)
+            .enumerate()
+            .find(|(_, (x, y))| (*x - *y).abs() > eps || x.is_nan() != y.is_nan())
+        {
+            panic!(
+                "the vectors differ at index {}: {} vs {} (difference {:e}, eps {:e})",
+                index,
+                x,
+                y,
+                (x - y).abs(),
+                eps
+            );
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "the vectors differ at index 2: 3 vs 3.5")]
+    fn test_assert_vectors_close_reports_first_difference() {
+        assert_vectors_close(&[1.0, 2.0, 3.0, 4.0], &[1.0, 2.0 + 1e-12, 3.5, 5.0], 1e-9);
+    }
+
+    #[test]
+    fn test_products_f64() {
+        let order = 5;
+        let n = 1 << order;
+        let mut rng = ChaCha8Rng::seed_from_u64(10);
+        let range = Uniform::new(-1.0, 1.0);
+        #[allow(non_snake_case)]
+        let A: Vec<f64> = (0..n * n).map(|_| rng.sample(range)).collect();
+        let v: Vec<f64> = (0..n).map(|_| rng.sample(range)).collect();
+
+        let mut naive = vec![0.0; n];
+        naive_matrix_vector_product(&A, &v, &mut naive, n);
+        #[allow(non_snake_case)]
+        let flattened_A = flatten_matrix(order, A, n);
+        let coordinate_iter: Vec<_> = HilbertIter::new(order).collect();
+        let mut hilbert = vec![0.0; n];
+        hilbert_matrix_vector_product(&flattened_A, &v, &mut hilbert, &coordinate_iter);
+        let mut hilbert_iter = vec![0.0; n];
+        hilbert_matrix_vector_product_iter(&flattened_A, &v, &mut hilbert_iter, order);
+
+        // The curve sums each row in a different order, so allow for rounding.
+        assert_vectors_close(&naive, &hilbert, 1e-9);
+        assert_vectors_close(&naive, &hilbert_iter, 1e-9);
+    }
+
+    #[test]
+    fn test_setup_hilbert_non_power_of_two() {
+        assert_eq!(padded_order(1), 0);
+        assert_eq!(padded_order(5), 3);
+        assert_eq!(padded_order(1024), 10);
+        let mut rng = ChaCha8Rng::seed_from_u64(10);
+        for n in [3, 5, 100, 1000] {
+            #[allow(non_snake_case)]
+            let (A, v) = super::setup_inputs(n, &mut rng);
+            let mut expected = vec![0; n];
+            naive_matrix_vector_product(&A, &v, &mut expected, n);
+            #[allow(non_snake_case)]
+            let (coordinate_iter, flattened_A) = super::setup_hilbert(n, A);
+            assert_eq!(coordinate_iter.len(), n * n);
+            assert!(coordinate_iter
+                .iter()
+                .enumerate()
+                .all(|(k, (t, _))| k == *t));
+            let mut output = vec![0; n];
+            hilbert_matrix_vector_product(&flattened_A, &v, &mut output, &coordinate_iter);
+            assert_eq!(output, expected, "n = {}", n);
+        }
+    }
+
+    #[test]
+    fn test_run_experiment() {
+        let cfg = ExperimentConfig::builder()
+            .sizes([4, 8, 6])
+            .repetitions(1)
+            .seed(3)
+            .build();
+        assert_eq!(cfg.curve, CurveKind::Hilbert);
+        for curve in CurveKind::variants() {
+            let rows = run_experiment(&ExperimentConfig {
+                curve,
+                ..cfg.clone()
+            });
+            let sizes: Vec<_> = rows.iter().map(|row| row.n).collect();
+            assert_eq!(sizes, [4, 8, 6]);
+            assert!(rows.iter().all(|row| row.curve == curve
+                && row.label == curve.label()
+                && row.threads == 1
+                && row.seconds >= 0.));
+        }
+    }
+
+    #[test]
+    fn test_write_csv() {
+        let cfg = ExperimentConfig::builder()
+            .sizes([4, 8])
+            .repetitions(1)
+            .curve(CurveKind::Snake)
+            .build();
+        let rows = run_experiment(&cfg);
+        let mut out = Vec::new();
+        write_csv(&rows, &mut out).unwrap();
+
+        let mut reader = csv::Reader::from_reader(out.as_slice());
+        assert_eq!(
+            reader.headers().unwrap(),
+            vec![
+                "label",
+                "threads",
+                "n",
+                "seconds",
+                "cycles",
+                "branches",
+                "missed_branches",
+                "instructions"
+            ]
+        );
+        let records: Vec<_> = reader.records().map(Result::unwrap).collect();
+        assert_eq!(records.len(), rows.len());
+        for (record, row) in records.iter().zip(&rows) {
+            assert_eq!(record.len(), 8);
+            assert_eq!(&record[0], "snake");
+            assert_eq!(&record[1], "1");
+            assert_eq!(record[2].parse::<usize>().unwrap(), row.n);
+            assert_eq!(record[3].parse::<f64>().unwrap(), row.seconds);
+            #[cfg(not(perf_counters))]
+            assert!(record.iter().skip(4).all(str::is_empty));
+        }
+    }
+
+    #[cfg(perf_counters)]
+    #[test]
+    fn test_count_perf() {
+        let mut sum = 0u64;
+        let counters = crate::count_perf(10, || {
+            for k in 0..1000 {
+                sum = std::hint::black_box(sum.wrapping_add(k));
+            }
+        })
+        .unwrap();
+        assert!(counters.instructions > 0., "{:?}", counters);
+    }
+
+    #[test]
+    fn test_hilbert_matrix_matrix_product() {
+        let mut rng = ChaCha8Rng::seed_from_u64(10);
+        // One tile, and 2x2 and 4x4 tiles of `MATRIX_TILE`.
+        for n in [1, 8, 2usize.pow(6), 2usize.pow(7)] {
+            #[allow(non_snake_case)]
+            let (A, _) = super::setup_inputs(n, &mut rng);
+            #[allow(non_snake_case)]
+            let (B, _) = super::setup_inputs(n, &mut rng);
+            let mut expected = vec![1; n * n];
+            naive_matrix_matrix_product(&A, &B, &mut expected, n);
+
+            #[allow(non_snake_case)]
+            let (hilbert_iter, flattened_A) = super::setup_hilbert(n, A);
+            #[allow(non_snake_case)]
+            let (_, flattened_B) = super::setup_hilbert(n, B);
+            let mut output = vec![1; n * n];
+            hilbert_matrix_matrix_product(&flattened_A, &flattened_B, &mut output, &hilbert_iter);
+
+            let mut flattened_expected = vec![0; n * n];
+            flatten_matrix_into(&hilbert_iter, &expected, &mut flattened_expected, n);
+            assert_eq!(output, flattened_expected, "n = {}", n);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "the curve must cover a 2^k x 2^k matrix, got 36 cells")]
+    fn test_hilbert_matrix_matrix_product_rejects_padded_curve() {
+        let (hilbert_iter, flattened) = super::setup_hilbert(6, vec![1; 36]);
+        let mut output = vec![0; 36];
+        hilbert_matrix_matrix_product(&flattened, &flattened, &mut output, &hilbert_iter);
+    }
+
+    #[test]
+    fn test_hilbert_transpose() {
+        let mut rng = ChaCha8Rng::seed_from_u64(10);
+        for n in [1, 2, 3, 8, 13, 32] {
+            #[allow(non_snake_case)]
+            let (A, _) = super::setup_inputs(n, &mut rng);
+            let expected = naive_transpose(&A, n);
+            assert_eq!(naive_transpose(&expected, n), A);
+
+            let (hilbert_iter, _) = super::setup_hilbert(n, A.clone());
+            assert_eq!(
+                hilbert_transpose(&A, n, &hilbert_iter),
+                expected,
+                "n = {}",
+                n
+            );
+        }
+    }
+
+    #[test]
+    fn test_validate_curve() {
+        for depth in 1..=7 {
+            assert_eq!(validate_curve(depth), Ok(()), "depth = {}", depth);
+        }
+    }
+
+    #[test]
+    fn test_curve_to_svg() {
+        let svg = curve_to_svg(2, 10.);
+        assert!(svg.contains(r#"viewBox="0 0 40 40""#), "{}", svg);
+
+        let points = svg.split("points=\"").nth(1).unwrap();
+        let points: Vec<_> = points[..points.find('"').unwrap()].split(' ').collect();
+        assert_eq!(points.len(), 16);
+        assert_eq!(points[0], "5,5");
+        // The curve of order 2 starts with a step to the right.
+        assert_eq!(points[1], "15,5");
+
+        // Well-formed: every tag is closed, in order.
+        let mut open = Vec::new();
+        for tag in svg.split('<').skip(1) {
+            let tag = &tag[..tag.find('>').unwrap()];
+            if let Some(name) = tag.strip_prefix('/') {
+                assert_eq!(open.pop(), Some(name));
+            } else if !tag.ends_with('/') {
+                open.push(tag.split(' ').next().unwrap());
+            }
+        }
+        assert!(open.is_empty(), "unclosed tags {:?}", open);
+    }
+
+    #[test]
+    fn test_hilbert_lut() {
+        for order in 1..=4 {
+            for (t, (i, j)) in HilbertIter::new(order) {
+                assert_eq!(hilbert_to_xy_lut(t, order), (i, j));
+                assert_eq!(xy_to_hilbert_lut(i, j, order), t);
+            }
+        }
+        // Beyond `LUT_MAX_ORDER` both fall back to the bit algorithm.
+        let order = LUT_MAX_ORDER + 1;
+        assert_eq!(hilbert_to_xy_lut(123, order), hilbert_to_xy(123, order));
+        assert_eq!(xy_to_hilbert_lut(5, 17, order), xy_to_hilbert(5, 17, order));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "(0, 3) is outside the grid")]
+    fn test_xy_to_hilbert_lut_outside_grid() {
+        xy_to_hilbert_lut(0, 3, 1);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "the index is past the end of the curve")]
+    fn test_hilbert_to_xy_lut_past_end() {
+        hilbert_to_xy_lut(5, 1);
+    }
+
+    #[test]
+    fn test_blocked_matrix_vector_product() {
+        let mut rng = ChaCha8Rng::seed_from_u64(10);
+        for n in [1, 7, 16, 33] {
+            #[allow(non_snake_case)]
+            let (A, v) = super::setup_inputs(n, &mut rng);
+            let mut expected = vec![0; n];
+            naive_matrix_vector_product(&A, &v, &mut expected, n);
+            for block in [1, 4, 5, 16, 64] {
+                let mut output = vec![0; n];
+                blocked_matrix_vector_product(&A, &v, &mut output, n, block);
+                assert_eq!(output, expected, "n = {}, block = {}", n, block);
+            }
+        }
+    }
+
+    #[test]
+    fn test_hilbert_iter_coords() {
+        for order in 0..5 {
+            let expected: Vec<_> = HilbertIter::new(order).map(|(_, c)| c).collect();
+            assert_eq!(
+                HilbertIter::new(order).coords().collect::<Vec<_>>(),
+                expected
+            );
+            assert_eq!(HilbertIter::new(order).coords().len(), expected.len());
+            assert_eq!(
+                HilbertIter::new(order).coords().rev().collect::<Vec<_>>(),
+                expected.into_iter().rev().collect::<Vec<_>>()
+            );
+        }
+    }
 
-        // reorder data
+    #[test]
+    fn test_log2_exact() {
+        assert_eq!(log2_exact(0), None);
+        assert_eq!(log2_exact(1), Some(0));
+        assert_eq!(log2_exact(1000), None);
+        assert_eq!(log2_exact(1024), Some(10));
+
+        // `setup_hilbert` pads instead of rounding down: all of a 1000 x 1000
+        // matrix ends up on the curve.
+        assert_eq!(padded_order(1000), 10);
+        assert_eq!(padded_order(1024), 10);
+        let (curve, _) = super::setup_hilbert(1000, vec![0; 1000 * 1000]);
+        assert_eq!(curve.len(), 1000 * 1000);
+    }
+
+    #[test]
+    fn test_log2() {
+        let mut expected = 0;
+        for n in 1..=1usize << 20 {
+            if n == 2 << expected {
+                expected += 1;
+            }
+            assert_eq!(log2(n), expected, "n = {}", n);
+        }
+        // Where an `f64` rounds `n` up to the next power of two.
+        assert_eq!(log2((1 << 60) - 1), 59);
+        assert_eq!(log2(usize::MAX), usize::BITS as usize - 1);
+    }
+
+    /// `(depth, seed, low, width)` for the product properties; shrinks
+    /// towards small curves and narrow value ranges near zero.
+    fn product_inputs() -> impl Strategy<Value = (usize, u64, i32, i32)> {
+        (1..=9usize, any::<u64>(), -100..100i32, 1..=100i32)
+    }
+
+    proptest! {
+        // Up to 512 x 512 matrices per case in a debug build.
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn prop_hilbert_product_matches_naive((depth, seed, low, width) in product_inputs()) {
+            let n = 1 << depth;
+            let mut rng = ChaCha8Rng::seed_from_u64(seed);
+            #[allow(non_snake_case)]
+            let A = make_matrix(n, low, low + width, &mut rng);
+            let range = Uniform::new(low, low + width);
+            let v: Vec<_> = (0..n).map(|_| rng.sample(range)).collect();
+
+            let mut expected = vec![0; n];
+            naive_matrix_vector_product(&A, &v, &mut expected, n);
+            #[allow(non_snake_case)]
+            let (hilbert_iter, flattened_A) = super::setup_hilbert(n, A);
+            let mut output = vec![0; n];
+            hilbert_matrix_vector_product(&flattened_A, &v, &mut output, &hilbert_iter);
+            prop_assert_eq!(output, expected);
+        }
+    }
+
+    #[test]
+    fn test_hilbert_matrix_vector_product_streaming() {
+        let mut rng = ChaCha8Rng::seed_from_u64(10);
+        for depth in 0..7 {
+            let n = 1 << depth;
+            #[allow(non_snake_case)]
+            let (A, v) = super::setup_inputs(n, &mut rng);
+            let mut output = vec![0; n];
+            hilbert_matrix_vector_product_streaming(&A, &v, &mut output, depth);
+
+            #[allow(non_snake_case)]
+            let (hilbert_iter, flattened_A) = super::setup_hilbert(n, A);
+            let mut expected = vec![0; n];
+            hilbert_matrix_vector_product(&flattened_A, &v, &mut expected, &hilbert_iter);
+            assert_eq!(output, expected, "depth = {}", depth);
+        }
+    }
+
+    #[test]
+    fn test_peano_iter_visits_every_cell_once() {
+        for depth in 0..=3 {
+            let n = 3usize.pow(depth as u32);
+            let points: Vec<_> = peano_iter(depth).collect();
+            assert_eq!(points.len(), n * n);
+            let mut visited = vec![false; n * n];
+            for (index, (t, (i, j))) in points.iter().enumerate() {
+                assert_eq!(*t, index);
+                assert!(*i < n && *j < n, "({}, {}) is outside the grid", i, j);
+                assert!(
+                    !visited[flat_index(*i, *j, n)],
+                    "({}, {}) visited twice",
+                    i,
+                    j
+                );
+                visited[flat_index(*i, *j, n)] = true;
+            }
+            for pair in points.windows(2) {
+                let ((_, (i0, j0)), (_, (i1, j1))) = (pair[0], pair[1]);
+                assert_eq!(i0.abs_diff(i1) + j0.abs_diff(j1), 1);
+            }
+            assert_eq!(points.last().unwrap().1, (n - 1, n - 1));
+        }
+    }
+
+    #[test]
+    fn test_setup_peano() {
+        let mut rng = ChaCha8Rng::seed_from_u64(10);
+        for n in [1, 9, 10, 27] {
+            #[allow(non_snake_case)]
+            let (A, v) = super::setup_inputs(n, &mut rng);
+            let mut expected = vec![0; n];
+            naive_matrix_vector_product(&A, &v, &mut expected, n);
+
+            #[allow(non_snake_case)]
+            let (peano, flattened_A) = super::setup_peano(n, A);
+            assert_eq!(peano.len(), n * n);
+            let mut output = vec![0; n];
+            hilbert_matrix_vector_product(&flattened_A, &v, &mut output, &peano);
+            assert_eq!(output, expected, "n = {}", n);
+        }
+    }
+
+    #[test]
+    fn test_setup_curve() {
+        let mut rng = ChaCha8Rng::seed_from_u64(10);
+        for n in [1, 27, 100] {
+            #[allow(non_snake_case)]
+            let (A, v) = super::setup_inputs(n, &mut rng);
+            let mut expected = vec![0; n];
+            naive_matrix_vector_product(&A, &v, &mut expected, n);
+            for kind in CurveKind::variants() {
+                #[allow(non_snake_case)]
+                let (coordinates, flattened_A) = setup_curve(kind, n, A.clone());
+                let mut output = vec![0; n];
+                hilbert_matrix_vector_product(&flattened_A, &v, &mut output, &coordinates);
+                assert_eq!(output, expected, "{:?}, n = {}", kind, n);
+            }
+        }
+    }
+
+    #[test]
+    fn test_hilbert_curve_into_iterator() {
+        let curve = HilbertCurve::new(3);
+        let mut rng = ChaCha8Rng::seed_from_u64(10);
+        for _ in 0..2 {
+            #[allow(non_snake_case)]
+            let (A, v) = super::setup_inputs(curve.n(), &mut rng);
+            let mut expected = vec![0; curve.n()];
+            naive_matrix_vector_product(&A, &v, &mut expected, curve.n());
+
+            let mut output = vec![0; curve.n()];
+            let mut visited = 0;
+            for (_, (i, j)) in &curve {
+                output[*i] += A[flat_index(*i, *j, curve.n())] * v[*j];
+                visited += 1;
+            }
+            assert_eq!(visited, 64);
+            assert_eq!(output, expected);
+        }
+        assert!((&curve).into_iter().eq(curve.coordinates()));
+    }
+
+    #[test]
+    fn test_hilbert_range_bbox_full_range() {
+        for order in 0..=5 {
+            let last = (1 << (2 * order)) - 1;
+            let side = (1 << order) - 1;
+            assert_eq!(hilbert_range_bbox(0, last, order), ((0, 0), (side, side)));
+        }
+    }
+
+    #[test]
+    fn test_hilbert_range_bbox_single_index() {
+        let order = 3;
+        for d in 0..1 << (2 * order) {
+            let cell = hilbert_to_xy(d, order);
+            assert_eq!(hilbert_range_bbox(d, d, order), (cell, cell));
+        }
+    }
+
+    #[test]
+    fn test_hilbert_range_bbox_matches_walk() {
+        let order = 3;
+        let cells: Vec<_> = (0..1 << (2 * order))
+            .map(|d| hilbert_to_xy(d, order))
+            .collect();
+        for start in 0..cells.len() {
+            for end in start..cells.len() {
+                let range = &cells[start..=end];
+                let min = (
+                    range.iter().map(|c| c.0).min().unwrap(),
+                    range.iter().map(|c| c.1).min().unwrap(),
+                );
+                let max = (
+                    range.iter().map(|c| c.0).max().unwrap(),
+                    range.iter().map(|c| c.1).max().unwrap(),
+                );
+                assert_eq!(hilbert_range_bbox(start, end, order), (min, max));
+            }
+        }
+    }
+
+    #[test]
+    fn test_identity_product_returns_v() {
+        let mut rng = ChaCha8Rng::seed_from_u64(10);
+        for n in [1, 2, 3, 5, 8, 12, 16, 33] {
+            let (_, v) = super::setup_inputs(n, &mut rng);
+
+            let mut output = vec![0; n];
+            naive_matrix_vector_product(&identity_matrix(n), &v, &mut output, n);
+            assert_eq!(output, v, "naive, n = {}", n);
+
+            #[allow(non_snake_case)]
+            let (coordinate_iter, flattened_A) = super::setup_hilbert(n, identity_matrix(n));
+            let mut output = vec![0; n];
+            hilbert_matrix_vector_product(&flattened_A, &v, &mut output, &coordinate_iter);
+            assert_eq!(output, v, "hilbert, n = {}", n);
+        }
+    }
+
+    #[test]
+    fn test_diagonal_and_constant_products() {
+        let v = vec![1, -2, 3, 4, 5];
+        let n = v.len();
+
+        let diag = [2, 0, -1, 7, 3];
+        let mut output = vec![0; n];
+        naive_matrix_vector_product(&diagonal_matrix(&diag), &v, &mut output, n);
+        assert_eq!(output, [2, 0, -3, 28, 15]);
+
+        let mut output = vec![0; n];
+        naive_matrix_vector_product(&constant_matrix(n, 3), &v, &mut output, n);
+        assert_eq!(output, [33; 5]);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "output has 3 elements, expected 4")]
+    fn test_naive_product_output_length_mismatch() {
+        let mut output = vec![0; 3];
+        naive_matrix_vector_product(&identity_matrix(4), &[1, 2, 3, 4], &mut output, 4);
+    }
+
+    #[test]
+    fn test_hilbert_permutation() {
+        let mut rng = ChaCha8Rng::seed_from_u64(10);
+        for n in [1, 2, 3, 7, 8, 16, 20] {
+            #[allow(non_snake_case)]
+            let (A, _) = super::setup_inputs(n, &mut rng);
+            let perm = hilbert_permutation(n);
+
+            #[allow(non_snake_case)]
+            let flattened_A: Vec<_> = perm.iter().map(|&k| A[k]).collect();
+            assert_eq!(flattened_A, flatten_matrix(padded_order(n), A.clone(), n));
+
+            let mut restored = vec![0; n * n];
+            for (t, &k) in perm.iter().enumerate() {
+                restored[k] = flattened_A[t];
+            }
+            assert_eq!(restored, A, "n = {}", n);
+        }
+    }
+
+    #[test]
+    fn test_unflatten_matrix_round_trip() {
+        let mut rng = ChaCha8Rng::seed_from_u64(10);
+        for n in [1, 2, 3, 5, 8, 13, 32] {
+            let perm = hilbert_permutation(n);
+            let inverse = hilbert_permutation_inverse(n);
+            for (t, &k) in perm.iter().enumerate() {
+                assert_eq!(inverse[k], t);
+            }
+
+            #[allow(non_snake_case)]
+            let (A, _) = super::setup_inputs(n, &mut rng);
+            #[allow(non_snake_case)]
+            let flattened_A = flatten_matrix(padded_order(n), A.clone(), n);
+            assert_eq!(unflatten_matrix(&flattened_A, &inverse, n), A, "n = {}", n);
+        }
+    }
+
+    #[test]
+    fn test_hilbert_product_windowed() {
+        let mut rng = ChaCha8Rng::seed_from_u64(10);
+        for depth in 0..=7 {
+            let n = 1 << depth;
+            #[allow(non_snake_case)]
+            let (A, v) = super::setup_inputs(n, &mut rng);
+            let mut expected = vec![0; n];
+            naive_matrix_vector_product(&A, &v, &mut expected, n);
+            #[allow(non_snake_case)]
+            let flattened_A = flatten_matrix(depth, A, n);
+            for window_order in [0, 1, 3, DEFAULT_WINDOW_ORDER, 10] {
+                let mut output = vec![0; n];
+                hilbert_matrix_vector_product_windowed(
+                    &flattened_A,
+                    &v,
+                    &mut output,
+                    depth,
+                    window_order,
+                );
+                assert_eq!(
+                    output, expected,
+                    "depth {}, window order {}",
+                    depth, window_order
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_naive_product_column_major() {
+        let mut rng = ChaCha8Rng::seed_from_u64(10);
+        for n in [1, 3, 16, 33] {
+            #[allow(non_snake_case)]
+            let (A, v) = super::setup_inputs(n, &mut rng);
+            let mut expected = vec![0; n];
+            naive_matrix_vector_product(&A, &v, &mut expected, n);
+
+            #[allow(non_snake_case)]
+            let A_colmajor: Vec<_> = (0..n * n).map(|k| A[flat_index(k % n, k / n, n)]).collect();
+            let mut output = vec![0; n];
+            naive_matrix_vector_product(&ColumnMajor(&A_colmajor), &v, &mut output, n);
+            assert_eq!(output, expected, "n = {}", n);
+        }
+    }
+
+    #[test]
+    fn test_naive_product_fn_matrix() {
+        let f = |i: usize, j: usize| (i + j) as i32;
+        for n in [1, 4, 17] {
+            #[allow(non_snake_case)]
+            let A: Vec<_> = (0..n).flat_map(|i| (0..n).map(move |j| f(i, j))).collect();
+            let v: Vec<_> = (0..n as i32).map(|x| x - 3).collect();
+            let mut expected = vec![0; n];
+            naive_matrix_vector_product(&A, &v, &mut expected, n);
+
+            let mut output = vec![0; n];
+            naive_matrix_vector_product(&FnMatrix(f), &v, &mut output, n);
+            assert_eq!(output, expected, "n = {}", n);
+        }
+    }
+
+    #[cfg(feature = "safe-index")]
+    #[test]
+    fn test_safe_index_short_flattened_a() {
+        let mut rng = ChaCha8Rng::seed_from_u64(10);
+        let n = 16;
         #[allow(non_snake_case)]
-        let (depth, flattened_A) = super::setup_hilbert_iter(n, A.clone());
+        let (A, v) = super::setup_inputs(n, &mut rng);
+        #[allow(non_snake_case)]
+        let (coordinate_iter, flattened_A) = super::setup_hilbert(n, A);
+        for _ in 0..50 {
+            let len = rng.gen_range(0..n * n);
+            let short = &flattened_A[..len];
+            let panic = std::panic::catch_unwind(|| {
+                let mut output = vec![0; n];
+                hilbert_matrix_vector_product(short, &v, &mut output, &coordinate_iter);
+            })
+            .unwrap_err();
+            let message = panic.downcast_ref::<String>().unwrap();
+            assert_eq!(
+                message,
+                &format!(
+                    "flattened_A[{}] is out of bounds: flattened_A has {} elements",
+                    coordinate_iter.iter().find(|(t, _)| *t >= len).unwrap().0,
+                    len
+                )
+            );
+        }
+    }
 
-        // Hilbert Product
-        let _ = timeit_loops! {timeit_count,
-            {hilbert_matrix_vector_product_iter(&flattened_A,&v, &mut output2, depth);}
+    #[cfg(feature = "safe-index")]
+    #[test]
+    #[should_panic(expected = "output[4] is out of bounds: output has 4 elements")]
+    fn test_safe_index_unchecked_product() {
+        let coordinate_iter: Vec<_> = HilbertIter::new(3).collect();
+        // The curve is for an 8x8 matrix but `v` and `output` are too short
+        // for it; without the feature this would be undefined behaviour.
+        let mut output = [0; 4];
+        unsafe {
+            crate::hilbert_matrix_vector_product_unchecked(
+                &[1; 64],
+                &[1; 4],
+                &mut output,
+                &coordinate_iter,
+            )
         };
+    }
 
-        // non-iterative version
-        let (hilbert_iter, flattened_a) = super::setup_hilbert(n, A);
+    #[test]
+    fn test_hilbert_blocks() {
+        for depth in 0..=6 {
+            let curve: Vec<_> = HilbertIter::new(depth).collect();
+            for block_order in 0..=depth + 1 {
+                let blocks: Vec<_> = hilbert_blocks(depth, block_order).collect();
+                let side = 1 << block_order.min(depth);
+                for block in &blocks {
+                    assert_eq!(block.len(), side * side);
+                    // Each block fills one aligned tile.
+                    let (ti, tj) = (block[0].1 .0 / side, block[0].1 .1 / side);
+                    assert!(block
+                        .iter()
+                        .all(|(_, (i, j))| (i / side, j / side) == (ti, tj)));
+                }
+                assert_eq!(
+                    blocks.concat(),
+                    curve,
+                    "depth {}, block order {}",
+                    depth,
+                    block_order
+                );
+            }
+        }
+    }
 
-        let _ = timeit_loops! {timeit_count,
-            {hilbert_matrix_vector_product(&flattened_a,&v, &mut output3, &hilbert_iter);}
-        };
+    #[test]
+    fn test_try_setup_hilbert_dimension_mismatch() {
+        assert_eq!(
+            super::try_setup_hilbert(4, vec![0; 15]),
+            Err(HilbertError::DimensionMismatch { n: 4, len: 15 })
+        );
+        assert!(super::try_setup_hilbert(4, vec![0; 16]).is_ok());
+    }
 
-        assert_eq!(output1, output2);
-        assert_eq!(output1, output3);
-        assert_yaml_snapshot!(output2);
+    #[test]
+    fn test_hilbert_iter_exact_size() {
+        for order in 0..6 {
+            let mut iter = HilbertIter::new(order);
+            let len = iter.len();
+            assert_eq!(len, 1 << (2 * order));
+            assert_eq!(iter.by_ref().take(len / 2).count(), len / 2);
+            assert_eq!(iter.len(), len - len / 2);
+            assert_eq!(iter.by_ref().count(), len - len / 2);
+            assert_eq!(iter.len(), 0);
+            assert_eq!(iter.next(), None);
+        }
+    }
+
+    /// The largest coordinates the curve of `depth` visits.
+    fn curve_extent(depth: usize) -> Coordinates {
+        HilbertIter::new(depth)
+            .coords()
+            .fold((0, 0), |(i_max, j_max), (i, j)| {
+                (i_max.max(i), j_max.max(j))
+            })
+    }
+
+    #[test]
+    fn test_hilbert_iter_stays_in_grid() {
+        // The decrements are guarded by debug asserts, so this also checks
+        // that no move underflows.
+        for depth in 0..=11 {
+            let n = 1 << depth;
+            assert_eq!(curve_extent(depth), (n - 1, n - 1));
+        }
+    }
+
+    #[test]
+    #[ignore = "takes about 10 s and 3 GB in a debug build"]
+    fn test_hilbert_iter_stays_in_grid_deep() {
+        for depth in 12..=13 {
+            let n = 1 << depth;
+            assert_eq!(curve_extent(depth), (n - 1, n - 1));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "exceeds MAX_DEPTH")]
+    fn test_hilbert_iter_max_depth() {
+        HilbertIter::new(MAX_DEPTH + 1);
+    }
+
+    #[test]
+    fn test_flatten_matrix_into() {
+        let mut rng = ChaCha8Rng::seed_from_u64(10);
+        let mut out = vec![0; 100 * 100];
+        for n in [64, 100] {
+            #[allow(non_snake_case)]
+            let (A, _) = super::setup_inputs(n, &mut rng);
+            #[allow(non_snake_case)]
+            let (coordinate_iter, flattened_A) = super::setup_hilbert(n, A.clone());
+            let out = &mut out[..n * n];
+            flatten_matrix_into(&coordinate_iter, &A, out, n);
+            assert_eq!(out, &flattened_A[..]);
+        }
+    }
+
+    #[test]
+    fn test_dense_matrix() {
+        let mut m = DenseMatrix::from(vec![vec![1, 2], vec![3, 4]]);
+        assert_eq!(m.n(), 2);
+        assert_eq!(m.get(1, 0), Some(3));
+        assert_eq!(m.get(2, 0), None);
+        assert_eq!(m.get(0, 2), None);
+        m.set(0, 1, 5);
+        assert_eq!(m.as_slice(), &[1, 5, 3, 4]);
+        assert_eq!(m, DenseMatrix::from((vec![1, 5, 3, 4], 2)));
+        assert!(std::panic::catch_unwind(|| DenseMatrix::new(vec![0; 3], 2)).is_err());
+        assert!(std::panic::catch_unwind(move || m.clone().set(0, 2, 0)).is_err());
+
+        let n = 16;
+        let mut rng = ChaCha8Rng::seed_from_u64(10);
+        #[allow(non_snake_case)]
+        let (A, v) = super::setup_inputs(n, &mut rng);
+        let mut expected = vec![0; n];
+        naive_matrix_vector_product(&A, &v, &mut expected, n);
+        let m = DenseMatrix::new(A, n);
+        assert_eq!(m.naive_product(&v), expected);
+        #[allow(non_snake_case)]
+        let (coordinate_iter, flattened_A) = m.setup_hilbert();
+        let mut output = vec![0; n];
+        hilbert_matrix_vector_product(&flattened_A, &v, &mut output, &coordinate_iter);
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn test_morton() {
+        let z: Vec<_> = morton_iter(1)
+            .chain(morton_iter(2).skip(4).take(4))
+            .collect();
+        assert_eq!(
+            z,
+            vec![
+                (0, (0, 0)),
+                (1, (0, 1)),
+                (2, (1, 0)),
+                (3, (1, 1)),
+                (4, (0, 2)),
+                (5, (0, 3)),
+                (6, (1, 2)),
+                (7, (1, 3))
+            ]
+        );
+
+        let mut rng = ChaCha8Rng::seed_from_u64(10);
+        for n in [32, 100] {
+            #[allow(non_snake_case)]
+            let (A, v) = super::setup_inputs(n, &mut rng);
+            let mut expected = vec![0; n];
+            naive_matrix_vector_product(&A, &v, &mut expected, n);
+            #[allow(non_snake_case)]
+            let (coordinate_iter, flattened_A) = super::setup_morton(n, A);
+            let mut output = vec![0; n];
+            hilbert_matrix_vector_product(&flattened_A, &v, &mut output, &coordinate_iter);
+            assert_eq!(output, expected);
+        }
+    }
+
+    #[test]
+    fn test_morton_encode_round_trip() {
+        let bits = 4;
+        for i in 0..1 << bits {
+            for j in 0..1 << bits {
+                let code = morton_encode(&[i, j], bits);
+                assert_eq!(morton_decode(code, 2, bits), [i, j]);
+                for k in 0..1 << bits {
+                    let code = morton_encode(&[i, j, k], bits);
+                    assert_eq!(morton_decode(code, 3, bits), [i, j, k]);
+                }
+            }
+        }
+        // 3 x 42 bits fill most of a u128.
+        let point = [(1 << 42) - 1, 12345, 1 << 41];
+        assert_eq!(morton_decode(morton_encode(&point, 42), 3, 42), point);
+    }
+
+    #[test]
+    fn test_morton_encode_matches_morton_iter() {
+        let depth = 4;
+        let n = 1 << depth;
+        let mut cells: Vec<Coordinates> =
+            (0..n).flat_map(|i| (0..n).map(move |j| (i, j))).collect();
+        cells.sort_by_key(|&(i, j)| morton_encode(&[i, j], depth));
+        assert_eq!(
+            cells,
+            morton_iter(depth).map(|(_, ij)| ij).collect::<Vec<_>>()
+        );
+        for (t, (i, j)) in morton_iter(depth) {
+            assert_eq!(morton_encode(&[i, j], depth), t as u128);
+        }
+    }
+
+    #[test]
+    fn test_cached_coordinates() {
+        let a = cached_coordinates(4);
+        let b = cached_coordinates(4);
+        assert!(Arc::ptr_eq(&a, &b));
+        assert!(!Arc::ptr_eq(&a, &cached_coordinates(3)));
+        assert_eq!(*a, HilbertIter::new(4).collect::<Vec<_>>());
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_hilbert_coordinates_parallel() {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(3)
+            .build()
+            .unwrap();
+        for depth in 0..=8 {
+            let table = pool.install(|| crate::hilbert_coordinates_parallel(depth));
+            assert_eq!(table, HilbertIter::new(depth).collect::<Vec<_>>());
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_hilbert_product_parallel() {
+        let n = 2usize.pow(10);
+        let mut rng = ChaCha8Rng::seed_from_u64(10);
+        #[allow(non_snake_case)]
+        let (A, v) = super::setup_inputs(n, &mut rng);
+        #[allow(non_snake_case)]
+        let (coordinate_iter, flattened_A) = super::setup_hilbert(n, A);
+        let mut expected = vec![0; n];
+        hilbert_matrix_vector_product(&flattened_A, &v, &mut expected, &coordinate_iter);
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap();
+        let mut output = vec![0; n];
+        pool.install(|| {
+            crate::hilbert_matrix_vector_product_parallel(
+                &flattened_A,
+                &v,
+                &mut output,
+                &coordinate_iter,
+            )
+        });
+        assert_eq!(output, expected);
+    }
+
+    #[cfg(feature = "simd")]
+    #[test]
+    fn test_naive_product_simd() {
+        let mut rng = ChaCha8Rng::seed_from_u64(10);
+        for n in [1, 7, 8, 9, 64, 100] {
+            #[allow(non_snake_case)]
+            let (A, v) = super::setup_inputs(n, &mut rng);
+            let mut expected = vec![0; n];
+            naive_matrix_vector_product(&A, &v, &mut expected, n);
+            let mut output = vec![0; n];
+            crate::naive_matrix_vector_product_simd(&A, &v, &mut output, n);
+            assert_eq!(output, expected, "n = {}", n);
+        }
+    }
+
+    #[test]
+    fn test_naive_product_checked() {
+        use std::num::Wrapping;
+
+        let n = 4;
+        #[allow(non_snake_case)]
+        let A = vec![1 << 20; n * n];
+        let v = vec![1 << 12; n];
+        assert!(product_may_overflow(n, 1 << 20));
+        let checked = naive_matrix_vector_product_checked(&A, &v, n);
+        assert_eq!(checked, vec![1i64 << 34; n]);
+
+        // The i32 product wraps around: 2^34 is 0 mod 2^32.
+        let wrapping_a: Vec<_> = A.iter().map(|a| Wrapping(*a)).collect();
+        let wrapping_v: Vec<_> = v.iter().map(|x| Wrapping(*x)).collect();
+        let mut wrapped = vec![Wrapping(0); n];
+        naive_matrix_vector_product(&wrapping_a, &wrapping_v, &mut wrapped, n);
+        assert_eq!(wrapped, vec![Wrapping(0); n]);
+    }
+
+    #[test]
+    fn test_rectangular_product() {
+        let mut rng = ChaCha8Rng::seed_from_u64(10);
+        for (rows, cols) in [(3, 5), (5, 3), (1, 8), (20, 7)] {
+            let range = Uniform::new(-10, 10);
+            #[allow(non_snake_case)]
+            let A: Vec<i32> = (0..rows * cols).map(|_| rng.sample(range)).collect();
+            let m = DenseMatrix::with_shape(A, rows, cols);
+            let v: Vec<i32> = (0..cols).map(|_| rng.sample(range)).collect();
+            let expected: Vec<i32> = (0..rows)
+                .map(|i| (0..cols).map(|j| m.get(i, j).unwrap() * v[j]).sum())
+                .collect();
+            assert_eq!(m.naive_product(&v), expected);
+
+            #[allow(non_snake_case)]
+            let (coordinate_iter, flattened_A) = m.setup_hilbert();
+            assert_eq!(coordinate_iter.len(), rows * cols);
+            let mut output = vec![0; rows];
+            hilbert_matrix_vector_product(&flattened_A, &v, &mut output, &coordinate_iter);
+            assert_eq!(output, expected, "{}x{}", rows, cols);
+        }
+    }
+
+    #[test]
+    fn test_make_matrix_with() {
+        struct Constant(i32);
+        impl rand::distributions::Distribution<i32> for Constant {
+            fn sample<R: Rng + ?Sized>(&self, _: &mut R) -> i32 {
+                self.0
+            }
+        }
+
+        let n = 16;
+        let mut rng = ChaCha8Rng::seed_from_u64(10);
+        #[allow(non_snake_case)]
+        let A = make_matrix_with(n, &Constant(1), &mut rng);
+        assert_eq!(A, vec![1; n * n]);
+        let v: Vec<i32> = (1..=n as i32).collect();
+        let mut output = vec![0; n];
+        naive_matrix_vector_product(&A, &v, &mut output, n);
+        assert_eq!(output, vec![v.iter().sum::<i32>(); n]);
+
+        // `make_matrix` is `make_matrix_with` a `Uniform`.
+        assert_eq!(
+            make_matrix(n, 1, 11, &mut ChaCha8Rng::seed_from_u64(3)),
+            make_matrix_with(n, &Uniform::new(1, 11), &mut ChaCha8Rng::seed_from_u64(3))
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_hilbert_ordering_round_trip() {
+        let path = std::env::temp_dir().join(format!(
+            "hilbert_ordering_round_trip-{}.bin",
+            std::process::id()
+        ));
+        crate::HilbertOrdering::new(8).save_to(&path).unwrap();
+        let loaded = crate::HilbertOrdering::load_from(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.0, HilbertIter::new(8).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_hilbert_iter_rev() {
+        for order in 0..5 {
+            let mut forward: Vec<_> = HilbertIter::new(order).collect();
+            forward.reverse();
+            assert_eq!(HilbertIter::new(order).rev().collect::<Vec<_>>(), forward);
+        }
+
+        // Consuming from both ends meets in the middle without overlap.
+        let mut iter = HilbertIter::with_offset(2, (1, 1));
+        let mut seen = Vec::new();
+        while let Some(front) = iter.next() {
+            seen.push(front);
+            seen.extend(iter.next_back());
+        }
+        seen.sort_unstable();
+        assert_eq!(
+            seen,
+            HilbertIter::with_offset(2, (1, 1)).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_hilbert_product_soa() {
+        let n = 100;
+        let mut rng = ChaCha8Rng::seed_from_u64(10);
+        #[allow(non_snake_case)]
+        let (A, v) = super::setup_inputs(n, &mut rng);
+        #[allow(non_snake_case)]
+        let (coordinate_iter, flattened_A) = super::setup_hilbert(n, A);
+        let mut expected = vec![0; n];
+        hilbert_matrix_vector_product(&flattened_A, &v, &mut expected, &coordinate_iter);
+
+        let table = CoordinateTable::from(&coordinate_iter[..]);
+        assert_eq!(table.len(), n * n);
+        let mut output = vec![0; n];
+        hilbert_matrix_vector_product_soa(&flattened_A, &v, &mut output, &table);
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn test_hilbert_product_dense() {
+        let n = 100;
+        let mut rng = ChaCha8Rng::seed_from_u64(10);
+        #[allow(non_snake_case)]
+        let (A, v) = super::setup_inputs(n, &mut rng);
+        #[allow(non_snake_case)]
+        let (coordinate_iter, flattened_A) = super::setup_hilbert(n, A);
+        let mut expected = vec![0; n];
+        hilbert_matrix_vector_product(&flattened_A, &v, &mut expected, &coordinate_iter);
+
+        let coordinates: Vec<_> = coordinate_iter.iter().map(|(_, ij)| *ij).collect();
+        let mut output = vec![0; n];
+        hilbert_matrix_vector_product_dense(&flattened_A, &v, &mut output, &coordinates);
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn test_hilbert_iter_sequence() {
+        // Guards the exact emitted sequence, e.g. across changes to the grammar's representation.
+        for order in 0..=3 {
+            assert_yaml_snapshot!(
+                format!("hilbert_iter_order_{}", order),
+                HilbertIter::new(order).collect::<Vec<_>>()
+            );
+        }
     }
 }
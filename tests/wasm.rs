@@ -0,0 +1,22 @@
+//! Tests for the `wasm` bindings, run in Node.js:
+//! `cargo test --target wasm32-unknown-unknown --no-default-features --features wasm --test wasm`
+//! (needs `wasm-bindgen-test-runner` from `wasm-bindgen-cli` as the runner).
+#![cfg(all(feature = "wasm", target_arch = "wasm32"))]
+
+use jeremy_kun_math_rust::{wasm_curve_points, wasm_xy_to_hilbert};
+use wasm_bindgen_test::wasm_bindgen_test;
+
+#[wasm_bindgen_test]
+fn curve_points() {
+    assert_eq!(wasm_curve_points(0), [0, 0]);
+    assert_eq!(wasm_curve_points(1), [0, 0, 1, 0, 1, 1, 0, 1]);
+    assert_eq!(wasm_curve_points(2).len(), 2 * 16);
+}
+
+#[wasm_bindgen_test]
+fn xy_to_hilbert_matches_curve_points() {
+    let points = wasm_curve_points(3);
+    for (t, point) in points.chunks(2).enumerate() {
+        assert_eq!(wasm_xy_to_hilbert(point[0], point[1], 3), t as u32);
+    }
+}